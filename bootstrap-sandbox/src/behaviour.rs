@@ -0,0 +1,19 @@
+use libp2p::swarm::NetworkBehaviour;
+use mina_transport::{ConnectionManager, ConnectionManagerConfig};
+
+/// The RPC, and the connection manager that limits the connections
+/// and refuses the peers banned for sending garbage.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub rpc: libp2p_rpc_behaviour::Behaviour,
+    pub manager: ConnectionManager,
+}
+
+impl Behaviour {
+    pub fn new(rpc: libp2p_rpc_behaviour::Behaviour, manager: ConnectionManagerConfig) -> Self {
+        Behaviour {
+            rpc,
+            manager: ConnectionManager::new(manager),
+        }
+    }
+}
@@ -1,15 +1,21 @@
 use binprot::BinProtRead;
-use libp2p::{Swarm, futures::StreamExt, swarm::SwarmEvent, PeerId};
-use mina_p2p_messages::{rpc_kernel::{self, RpcMethod, ResponseHeader, ResponsePayload, QueryHeader}, rpc::GetBestTipV2};
-use libp2p_rpc_behaviour::{Behaviour, Event, StreamId, Received};
+use libp2p::{Swarm, futures::StreamExt, swarm::SwarmEvent, PeerId, Multiaddr};
+use mina_p2p_messages::{rpc_kernel::{self, RpcMethod, ResponseHeader, ResponsePayload, QueryHeader}, rpc::{GetBestTipV2, GetSomeInitialPeersV1ForV2}};
+use libp2p_rpc_behaviour::{BehaviourBuilder, Event, StreamId, Received};
 
 use thiserror::Error;
 
+use super::{
+    behaviour::{Behaviour, BehaviourEvent},
+    peer_exchange::PeerExchange,
+};
+
 pub struct Client {
     swarm: Swarm<Behaviour>,
     peer: Option<PeerId>,
     stream: Option<StreamId>,
     id: i64,
+    peer_exchange: PeerExchange,
 }
 
 #[derive(Debug, Error)]
@@ -23,12 +29,23 @@ pub enum ClientError {
 }
 
 impl Client {
-    pub fn new(swarm: Swarm<Behaviour>) -> Self {
+    /// Build the behaviour with the methods the client answers.
+    pub fn behaviour() -> libp2p_rpc_behaviour::Behaviour {
+        BehaviourBuilder::default()
+            .register_method::<GetBestTipV2>()
+            .register_method::<GetSomeInitialPeersV1ForV2>()
+            .build()
+    }
+
+    /// The `initial_peers` are advertised in `get_some_initial_peers`,
+    /// if empty, the client advertises the peers it is connected to.
+    pub fn new(swarm: Swarm<Behaviour>, initial_peers: Vec<Multiaddr>) -> Self {
         Client {
             swarm,
             peer: None,
             stream: None,
             id: 1,
+            peer_exchange: PeerExchange::new(initial_peers),
         }
     }
 
@@ -41,33 +58,42 @@ impl Client {
             if let Some(query) = query.take() {
                 self.swarm
                     .behaviour_mut()
+                    .rpc
                     .query::<M>(peer_id, stream_id, self.id, query)?;
                 self.id += 1;
             }
         }
 
         loop {
-            match self.swarm.next().await.ok_or(ClientError::Libp2p)? {
-                SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
+            let event = self.swarm.next().await.ok_or(ClientError::Libp2p)?;
+            self.peer_exchange.on_swarm_event(&event);
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Rpc((
+                    peer_id,
+                    Event::ConnectionEstablished,
+                ))) => {
                     log::info!("new connection {peer_id}");
 
                     self.peer = Some(peer_id);
-                    self.swarm.behaviour_mut().open(peer_id, 0);
+                    self.swarm.behaviour_mut().rpc.open(peer_id, 0);
                 }
-                SwarmEvent::Behaviour((peer_id, Event::ConnectionClosed)) => {
+                SwarmEvent::Behaviour(BehaviourEvent::Rpc((
+                    peer_id,
+                    Event::ConnectionClosed,
+                ))) => {
                     log::info!("connection closed {peer_id}");
                     if self.peer == Some(peer_id) {
                         self.peer = None;
                         // TODO: resend
                     }
                 }
-                SwarmEvent::Behaviour((
+                SwarmEvent::Behaviour(BehaviourEvent::Rpc((
                     peer_id,
                     Event::Stream {
                         stream_id,
                         received,
                     },
-                )) => match received {
+                ))) => match received {
                     Received::HandshakeDone => {
                         log::info!("new stream {peer_id} {stream_id:?}");
                         self.stream = Some(stream_id);
@@ -76,6 +102,7 @@ impl Client {
                             if let Some(query) = query.take() {
                                 self.swarm
                                     .behaviour_mut()
+                                    .rpc
                                     .query::<M>(peer_id, stream_id, self.id, query)?;
                                 self.id += 1;
                             }
@@ -87,7 +114,16 @@ impl Client {
                     Received::Query { header: QueryHeader { tag, version, id }, bytes } => {
                         if tag.to_string_lossy() == "get_best_tip" && version == 2 {
                             let _ = bytes;
-                            self.swarm.behaviour_mut().respond::<GetBestTipV2>(peer_id, stream_id, id, Ok(None)).unwrap();
+                            self.swarm.behaviour_mut().rpc.respond::<GetBestTipV2>(peer_id, stream_id, id, Ok(None)).unwrap();
+                        } else if tag.to_string_lossy() == GetSomeInitialPeersV1ForV2::NAME
+                            && version == GetSomeInitialPeersV1ForV2::VERSION
+                        {
+                            let peers = self.peer_exchange.peers(peer_id);
+                            self.swarm
+                                .behaviour_mut()
+                                .rpc
+                                .respond::<GetSomeInitialPeersV1ForV2>(peer_id, stream_id, id, Ok(peers))
+                                .unwrap();
                         } else {
                             log::warn!("unhandled query: {tag} {version}");
                         }
@@ -99,10 +135,15 @@ impl Client {
                         if id + 1 == self.id {
                             let mut bytes = bytes.as_slice();
                             let response =
-                                ResponsePayload::<M::Response>::binprot_read(&mut bytes)?
-                                    .0
-                                    .map_err(ClientError::InternalError)?
-                                    .0;
+                                match ResponsePayload::<M::Response>::binprot_read(&mut bytes) {
+                                    Ok(response) => response,
+                                    Err(err) => {
+                                        log::warn!("cannot decode the response of {peer_id}");
+                                        self.swarm.behaviour_mut().manager.ban(peer_id);
+                                        return Err(err.into());
+                                    }
+                                };
+                            let response = response.0.map_err(ClientError::InternalError)?.0;
                             return Ok(response);
                         }
                     }
@@ -0,0 +1,127 @@
+use std::{str::FromStr, time::Duration};
+
+use thiserror::Error;
+
+/// Misbehaviour of a replay peer, to exercise peer selection and failover of the node.
+/// Parsed from a string like `delay=500,drop=0.1,disconnect=100`.
+#[derive(Default, Clone, Debug)]
+pub struct FaultProfile {
+    /// Wait before answering each query.
+    pub delay: Duration,
+    /// Probability to never answer a query.
+    pub drop: f64,
+    /// Disconnect the peer after answering this many of its queries.
+    pub disconnect_after: Option<usize>,
+}
+
+#[derive(Debug, Error)]
+pub enum FaultProfileError {
+    #[error("expected `key=value`, got {0}")]
+    Syntax(String),
+    #[error("unknown fault {0}, expected `delay`, `drop` or `disconnect`")]
+    UnknownKey(String),
+    #[error("bad value {0}")]
+    BadValue(String),
+}
+
+impl FromStr for FaultProfile {
+    type Err = FaultProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut profile = FaultProfile::default();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| FaultProfileError::Syntax(item.to_owned()))?;
+            let bad_value = || FaultProfileError::BadValue(item.to_owned());
+            match key {
+                "delay" => {
+                    let ms = value.parse().map_err(|_| bad_value())?;
+                    profile.delay = Duration::from_millis(ms);
+                }
+                "drop" => {
+                    profile.drop = value.parse().map_err(|_| bad_value())?;
+                    if !(0.0..=1.0).contains(&profile.drop) {
+                        return Err(bad_value());
+                    }
+                }
+                "disconnect" => {
+                    profile.disconnect_after = Some(value.parse().map_err(|_| bad_value())?);
+                }
+                _ => return Err(FaultProfileError::UnknownKey(key.to_owned())),
+            }
+        }
+
+        Ok(profile)
+    }
+}
+
+impl FaultProfile {
+    pub fn should_drop(&self) -> bool {
+        self.drop > 0.0 && rand::random::<f64>() < self.drop
+    }
+
+    pub fn should_disconnect(&self, served: usize) -> bool {
+        self.disconnect_after.map_or(false, |limit| served >= limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FaultProfile, FaultProfileError};
+
+    #[test]
+    fn valid() {
+        let profile = "delay=500,drop=0.1,disconnect=100"
+            .parse::<FaultProfile>()
+            .unwrap();
+        assert_eq!(profile.delay, Duration::from_millis(500));
+        assert_eq!(profile.drop, 0.1);
+        assert_eq!(profile.disconnect_after, Some(100));
+        assert!(profile.should_disconnect(100));
+        assert!(!profile.should_disconnect(99));
+
+        let profile = "".parse::<FaultProfile>().unwrap();
+        assert!(profile.delay.is_zero());
+        assert!(!profile.should_drop());
+        assert!(!profile.should_disconnect(usize::MAX));
+
+        let profile = "drop=1".parse::<FaultProfile>().unwrap();
+        assert!(profile.should_drop());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            "delay".parse::<FaultProfile>(),
+            Err(FaultProfileError::Syntax(_))
+        ));
+        assert!(matches!(
+            "latency=5".parse::<FaultProfile>(),
+            Err(FaultProfileError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            "delay=-5".parse::<FaultProfile>(),
+            Err(FaultProfileError::BadValue(_))
+        ));
+        assert!(matches!(
+            "disconnect=often".parse::<FaultProfile>(),
+            Err(FaultProfileError::BadValue(_))
+        ));
+    }
+
+    #[test]
+    fn probability_out_of_range() {
+        for s in ["drop=1.5", "drop=-0.1", "drop=NaN", "drop=inf"] {
+            assert!(
+                matches!(
+                    s.parse::<FaultProfile>(),
+                    Err(FaultProfileError::BadValue(_))
+                ),
+                "{s}"
+            );
+        }
+    }
+}
@@ -1,19 +1,24 @@
 #![forbid(unsafe_code)]
 
+mod behaviour;
 mod client;
 mod snarked_ledger;
 mod bootstrap;
 mod check;
+mod query_log;
+mod fault;
+mod peer_exchange;
+mod session;
 
 mod record;
 mod replay;
 
-use std::{env, path::PathBuf};
+use std::{cell::RefCell, net::SocketAddr, path::PathBuf};
 
 use libp2p::{Multiaddr, futures::StreamExt};
 use libp2p_rpc_behaviour::BehaviourBuilder;
 use structopt::StructOpt;
-use mina_transport::ed25519::SecretKey;
+use mina_transport::{keystore, metrics::Metrics, ConnectionManagerConfig, TransportConfig};
 
 #[derive(StructOpt)]
 struct Args {
@@ -25,12 +30,38 @@ struct Args {
     listen: Vec<Multiaddr>,
     #[structopt(long)]
     peer: Vec<Multiaddr>,
+    /// Advertise these addresses in `get_some_initial_peers`,
+    /// by default advertise the peers the swarm is connected to.
+    #[structopt(long)]
+    initial_peer: Vec<Multiaddr>,
+    /// The keypair file in Mina format, created if missing, so the peer id is stable.
+    /// The password is taken from `MINA_LIBP2P_PASS`.
+    /// Without it the key is taken from `OPENMINA_P2P_SEC_KEY` or generated.
+    #[structopt(long)]
+    identity: Option<PathBuf>,
+    /// Count bytes and streams per peer and protocol,
+    /// and serve them in Prometheus text format on this address.
+    #[structopt(long)]
+    metrics: Option<SocketAddr>,
+    /// Also listen and dial QUIC addresses, e.g. `/ip4/127.0.0.1/udp/8302/quic-v1`.
+    #[structopt(long)]
+    quic: bool,
+    /// Also listen and dial WebRTC-direct addresses, e.g. `/ip4/127.0.0.1/udp/8303/webrtc`.
+    #[structopt(long)]
+    webrtc: bool,
+    /// Refuse inbound connections above this many, also counting those in the handshake.
+    #[structopt(long)]
+    max_inbound: Option<u32>,
     #[structopt(subcommand)]
     cmd: Command,
 }
 
 #[derive(StructOpt)]
 enum Command {
+    /// Generate a keypair file in Mina format, encrypted with `MINA_LIBP2P_PASS` if set.
+    Keygen {
+        path: PathBuf,
+    },
     Again {
         height: u32,
     },
@@ -39,7 +70,35 @@ enum Command {
         bootstrap: bool,
     },
     Replay {
-        height: u32,
+        /// The height of the recording. If several heights are given,
+        /// the peers of the swarm serve them in turn.
+        #[structopt(required = true)]
+        height: Vec<u32>,
+        /// Write every served query as a line of JSON into this file.
+        #[structopt(long)]
+        trace: Option<PathBuf>,
+        /// Record the queries of the peers with their timing, and the responses,
+        /// to replay them later with `replay-session`.
+        #[structopt(long)]
+        session: Option<PathBuf>,
+        /// Run this many independent peers, the peer `i` listens on the `--listen` addresses,
+        /// or on `/ip4/0.0.0.0/tcp/<base-port>`, with the port increased by `i`.
+        #[structopt(long, default_value = "1")]
+        swarm_size: usize,
+        #[structopt(long, default_value = "8302")]
+        base_port: u16,
+        /// Fault profile of the peers in order, e.g. `delay=500,drop=0.1,disconnect=100`.
+        /// Pass an empty string for a healthy peer.
+        #[structopt(long)]
+        fault: Vec<fault::FaultProfile>,
+    },
+    /// Issue the queries from the session recorded by `replay --session`
+    /// to the `--peer` and compare the answers with the recorded ones.
+    ReplaySession {
+        session: PathBuf,
+        /// Issue the queries one after another, without the recorded pauses.
+        #[structopt(long)]
+        no_delay: bool,
     },
     Empty,
     Test {
@@ -53,25 +112,49 @@ enum Command {
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let Args { path, chain_id, listen, peer, cmd } = Args::from_args();
-
-    let sk = env::var("OPENMINA_P2P_SEC_KEY")
-        .map(|key| {
-            let mut bytes = bs58::decode(key).with_check(Some(0x80)).into_vec().unwrap();
-            SecretKey::from_bytes(&mut bytes[1..]).unwrap()
-        })
-        .unwrap_or_else(|_| {
-            let mut bytes = rand::random::<[u8; 32]>();
-            log::info!("{}", bs58::encode(&bytes).with_check_version(0x80).into_string());
-            let sk = SecretKey::from_bytes(&mut bytes).unwrap();
-            sk
+    let Args {
+        path,
+        chain_id,
+        listen,
+        peer,
+        initial_peer,
+        identity,
+        metrics,
+        quic,
+        webrtc,
+        max_inbound,
+        cmd,
+    } = Args::from_args();
+
+    if let Command::Keygen { path } = &cmd {
+        let local_key = keystore::generate(path)?;
+        println!("{}", local_key.public().to_peer_id());
+        return Ok(());
+    }
+
+    let local_key = keystore::load_or_generate(identity.as_deref())?;
+
+    let mut transport = TransportConfig::default().quic(quic).webrtc(webrtc);
+    if let Some(addr) = metrics {
+        let metrics = Metrics::default();
+        transport = transport.metrics(metrics.clone());
+        tokio::spawn(async move {
+            if let Err(err) = metrics.serve_prometheus(addr).await {
+                log::error!("cannot serve metrics on {addr}: {err}");
+            }
         });
+    }
 
-    let local_key: libp2p::identity::Keypair = mina_transport::ed25519::Keypair::from(sk).into();
-    log::info!("{}", local_key.public().to_peer_id());
+    // a peer that sends garbage is banned, the dialed peers are redialed
+    let manager = ConnectionManagerConfig {
+        max_inbound,
+        max_pending_inbound: max_inbound,
+        peers: peer.clone(),
+        ..Default::default()
+    };
 
     // let listen_on = [
     //     "/ip6/::/tcp/8302".parse().unwrap(),
@@ -88,36 +171,79 @@ async fn main() {
     // .flatten();
 
     match cmd {
+        Command::Keygen { .. } => {}
         Command::Again { height } => {
             bootstrap::again(&path, height).await;
         }
         Command::Record { bootstrap } => {
-            let behaviour = BehaviourBuilder::default().build();
-            let swarm = mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour);
+            let behaviour = behaviour::Behaviour::new(client::Client::behaviour(), manager);
+            let swarm = transport
+                .listen_on(listen)
+                .peers(peer)
+                .build(local_key, chain_id.as_bytes(), behaviour)?;
 
-            record::run(swarm, &path, bootstrap).await
+            record::run(swarm, &path, bootstrap, initial_peer).await
         }
-        Command::Replay { height } => {
-            use mina_p2p_messages::rpc::{
-                GetBestTipV2, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
-                AnswerSyncLedgerQueryV2, GetTransitionChainV2, GetTransitionChainProofV1ForV2,
-            };
-
-            let behaviour = BehaviourBuilder::default()
-                .register_method::<GetBestTipV2>()
-                .register_method::<GetAncestryV2>()
-                .register_method::<GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>()
-                .register_method::<AnswerSyncLedgerQueryV2>()
-                .register_method::<GetTransitionChainV2>()
-                .register_method::<GetTransitionChainProofV1ForV2>()
-                .build();
-            let swarm = mina_transport::swarm(local_key, chain_id.as_bytes(), listen, [], behaviour);
-
-            replay::run(swarm, &path, height).await
+        Command::Replay {
+            height,
+            trace,
+            session,
+            swarm_size,
+            base_port,
+            fault,
+        } => {
+            if swarm_size > 1 {
+                let mut keys = vec![local_key];
+                keys.extend((1..swarm_size).map(|_| mina_transport::generate_identity()));
+                let listen = if listen.is_empty() {
+                    vec![format!("/ip4/0.0.0.0/tcp/{base_port}").parse()?]
+                } else {
+                    listen
+                };
+                let chain_id = chain_id.as_bytes();
+                replay::run_many(
+                    keys,
+                    transport,
+                    &manager,
+                    &listen,
+                    chain_id,
+                    &path,
+                    &height,
+                    trace,
+                    session,
+                    &fault,
+                    &initial_peer,
+                )
+                .await?
+            } else {
+                let behaviour = behaviour::Behaviour::new(replay::behaviour(), manager);
+                let swarm = transport
+                    .listen_on(listen)
+                    .build(local_key, chain_id.as_bytes(), behaviour)?;
+                let recording = RefCell::new(replay::Recording::load(&path, height[0]));
+                let fault = fault.first().cloned().unwrap_or_default();
+
+                let (trace, session) = (trace.as_deref(), session.as_deref());
+
+                replay::run(swarm, &recording, trace, session, fault, &initial_peer).await?
+            }
+        }
+        Command::ReplaySession { session, no_delay } => {
+            let behaviour = behaviour::Behaviour::new(client::Client::behaviour(), manager);
+            let swarm = transport
+                .listen_on(listen)
+                .peers(peer)
+                .build(local_key, chain_id.as_bytes(), behaviour)?;
+            let mut client = client::Client::new(swarm, initial_peer);
+
+            session::replay(&mut client, &session, no_delay).await
         }
         Command::Empty => {
             let behaviour = BehaviourBuilder::default().build();
-            let mut swarm = mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour);
+            let mut swarm = transport
+                .listen_on(listen)
+                .peers(peer)
+                .build(local_key, chain_id.as_bytes(), behaviour)?;
             loop {
                 swarm.next().await;
             }
@@ -129,4 +255,6 @@ async fn main() {
             check::test_graphql(&path, height, url);
         }
     }
+
+    Ok(())
 }
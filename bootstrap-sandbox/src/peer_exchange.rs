@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use libp2p::{
+    core::ConnectedPoint,
+    multiaddr::Protocol,
+    swarm::SwarmEvent,
+    Multiaddr, PeerId,
+};
+use mina_p2p_messages::v2;
+
+/// Answers `get_some_initial_peers`. Either the configured list of addresses,
+/// or, if the list is empty, the peers the swarm is connected to.
+#[derive(Default)]
+pub struct PeerExchange {
+    configured: Vec<Multiaddr>,
+    live: BTreeMap<PeerId, Multiaddr>,
+}
+
+impl PeerExchange {
+    pub fn new(configured: Vec<Multiaddr>) -> Self {
+        for addr in &configured {
+            if network_peer(addr).is_none() {
+                log::warn!("cannot advertise {addr}, need ip or dns, tcp and p2p");
+            }
+        }
+        PeerExchange {
+            configured,
+            live: BTreeMap::default(),
+        }
+    }
+
+    /// Track the addresses of the connected peers.
+    /// The address we dialed is known to be reachable, it replaces any other.
+    /// For an inbound connection take the remote address: go-libp2p dials
+    /// from its listening port, so for a Mina node it is the listening address.
+    pub fn on_swarm_event<TBehaviourOutEvent, THandlerErr>(
+        &mut self,
+        event: &SwarmEvent<TBehaviourOutEvent, THandlerErr>,
+    ) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                let (address, dialed) = match endpoint {
+                    ConnectedPoint::Dialer { address, .. } => (address, true),
+                    ConnectedPoint::Listener { send_back_addr, .. } => (send_back_addr, false),
+                };
+                let mut address = address.clone();
+                if !matches!(address.iter().last(), Some(Protocol::P2p(_))) {
+                    address.push(Protocol::P2p((*peer_id).into()));
+                }
+                if dialed {
+                    self.live.insert(*peer_id, address);
+                } else {
+                    self.live.entry(*peer_id).or_insert(address);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.live.remove(peer_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// The peers to advertise to the `requester`, excluding the requester itself.
+    pub fn peers(&self, requester: PeerId) -> Vec<v2::NetworkPeerPeerStableV1> {
+        let addrs = if self.configured.is_empty() {
+            self.live.values().collect::<Vec<_>>()
+        } else {
+            self.configured.iter().collect()
+        };
+        let requester = requester.to_string();
+        addrs
+            .into_iter()
+            .filter_map(network_peer)
+            .filter(|peer| peer.peer_id.0.as_ref() != requester.as_bytes())
+            .collect()
+    }
+}
+
+/// Convert the address into the form `get_some_initial_peers` uses.
+pub fn network_peer(addr: &Multiaddr) -> Option<v2::NetworkPeerPeerStableV1> {
+    let (mut host, mut port, mut peer_id) = (None, None, None);
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(ip) => host = Some(ip.to_string()),
+            Protocol::Ip6(ip) => host = Some(ip.to_string()),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                host = Some(name.to_string())
+            }
+            Protocol::Tcp(p) => port = Some(p),
+            Protocol::P2p(hash) => peer_id = PeerId::from_multihash(hash).ok(),
+            _ => {}
+        }
+    }
+
+    Some(v2::NetworkPeerPeerStableV1 {
+        host: host?.into_bytes().into(),
+        libp2p_port: (port? as i64).into(),
+        peer_id: v2::NetworkPeerPeerIdStableV1(peer_id?.to_string().into_bytes().into()),
+    })
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use libp2p::PeerId;
+use serde::Serialize;
+
+/// Statistics of the queries served by the replay, per connected peer.
+/// Optionally writes every query as a line of JSON into the trace file.
+pub struct QueryLog {
+    trace: Option<BufWriter<File>>,
+    peers: BTreeMap<PeerId, PeerStats>,
+}
+
+#[derive(Default)]
+struct PeerStats {
+    methods: BTreeMap<String, MethodStats>,
+    seen: BTreeSet<String>,
+}
+
+#[derive(Default, Clone)]
+struct MethodStats {
+    count: usize,
+    duplicates: usize,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    peer_id: String,
+    method: &'a str,
+    version: i32,
+    id: i64,
+    query: &'a serde_json::Value,
+    response_size: usize,
+    time_us: u64,
+}
+
+impl QueryLog {
+    pub fn new(trace: Option<&Path>) -> io::Result<Self> {
+        let trace = match trace {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        Ok(QueryLog {
+            trace,
+            peers: BTreeMap::default(),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        peer_id: PeerId,
+        method: &str,
+        version: i32,
+        id: i64,
+        query: &serde_json::Value,
+        response_size: usize,
+        time: Duration,
+    ) {
+        let peer = self.peers.entry(peer_id).or_default();
+        let stats = peer.methods.entry(format!("{method}:{version}")).or_default();
+        stats.count += 1;
+        stats.bytes += response_size;
+        if !peer.seen.insert(format!("{method}:{version}:{query}")) {
+            stats.duplicates += 1;
+            log::debug!("{peer_id} repeats {method} {query}");
+        }
+
+        if let Some(trace) = &mut self.trace {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let record = Record {
+                timestamp,
+                peer_id: peer_id.to_string(),
+                method,
+                version,
+                id,
+                query,
+                response_size,
+                time_us: time.as_micros() as u64,
+            };
+            let res = serde_json::to_writer(&mut *trace, &record)
+                .map_err(io::Error::from)
+                .and_then(|()| trace.write_all(b"\n"));
+            if let Err(err) = res {
+                log::error!("failed to write the query trace: {err}");
+            }
+        }
+    }
+
+    /// Print the summary to the log and flush the trace.
+    pub fn summary(&mut self) {
+        let mut total = BTreeMap::<&str, MethodStats>::new();
+        for (peer_id, peer) in &self.peers {
+            for (method, stats) in &peer.methods {
+                log::info!(
+                    "{peer_id} {method}: {} queries, {} duplicated, {} bytes",
+                    stats.count,
+                    stats.duplicates,
+                    stats.bytes,
+                );
+                let t = total.entry(method).or_default();
+                t.count += stats.count;
+                t.duplicates += stats.duplicates;
+                t.bytes += stats.bytes;
+            }
+        }
+        let mut bytes = 0;
+        for (method, stats) in &total {
+            log::info!(
+                "total {method}: {} queries, {} duplicated, {} bytes",
+                stats.count,
+                stats.duplicates,
+                stats.bytes,
+            );
+            bytes += stats.bytes;
+        }
+        log::info!("total: {} peers, {bytes} bytes", self.peers.len());
+
+        if let Some(trace) = &mut self.trace {
+            if let Err(err) = trace.flush() {
+                log::error!("failed to write the query trace: {err}");
+            }
+        }
+    }
+}
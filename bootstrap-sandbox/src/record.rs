@@ -6,7 +6,7 @@ use std::{
 };
 
 use binprot::{BinProtRead, BinProtWrite};
-use libp2p::Swarm;
+use libp2p::{Swarm, Multiaddr};
 use mina_p2p_messages::{
     rpc::{
         GetBestTipV2, WithHashV1, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
@@ -14,12 +14,17 @@ use mina_p2p_messages::{
     },
     v2,
 };
-use libp2p_rpc_behaviour::Behaviour;
-
-use super::{client::Client, bootstrap::Storage, snarked_ledger::SnarkedLedger};
+use super::{
+    behaviour::Behaviour, client::Client, bootstrap::Storage, snarked_ledger::SnarkedLedger,
+};
 
-pub async fn run(swarm: Swarm<Behaviour>, path_main: &Path, bootstrap: bool) {
-    let mut client = Client::new(swarm);
+pub async fn run(
+    swarm: Swarm<Behaviour>,
+    path_main: &Path,
+    bootstrap: bool,
+    initial_peers: Vec<Multiaddr>,
+) {
+    let mut client = Client::new(swarm, initial_peers);
 
     fs::create_dir_all(&path_main).unwrap();
 
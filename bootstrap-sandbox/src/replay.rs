@@ -1,213 +1,728 @@
 use std::{
+    cell::RefCell,
     fs::{File, self},
-    path::Path,
-    collections::{BTreeMap, BTreeSet},
+    io,
+    marker::PhantomData,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    collections::BTreeMap,
+    time::Instant,
 };
 
-use libp2p::{futures::StreamExt, swarm::SwarmEvent};
+use libp2p::{
+    futures::{future, stream::FuturesUnordered, StreamExt},
+    multiaddr::Protocol,
+    swarm::SwarmEvent,
+    Multiaddr, PeerId,
+};
+use thiserror::Error;
+use mina_transport::{rpc, ConnectionManagerConfig, Keypair, TransportConfig, TransportError};
 use mina_p2p_messages::{
     rpc::{
         GetBestTipV2, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
         AnswerSyncLedgerQueryV2, GetTransitionChainV2, GetTransitionChainProofV1ForV2,
+        GetTransitionKnowledgeV1ForV2, GetSomeInitialPeersV1ForV2,
     },
     rpc_kernel::{RpcMethod, QueryHeader, QueryPayload, RpcResult},
+    core::Info,
     v2,
 };
-use binprot::BinProtRead;
-use libp2p_rpc_behaviour::{Event, Received, Behaviour};
+use binprot::{BinProtRead, BinProtWrite};
+use libp2p_rpc_behaviour::{Event, Received, BehaviourBuilder, StreamId};
+
+use super::{
+    snarked_ledger::SnarkedLedger, query_log::QueryLog, fault::FaultProfile,
+    peer_exchange::PeerExchange, session::SessionWriter,
+    behaviour::{Behaviour, BehaviourEvent},
+};
 
-use super::snarked_ledger::SnarkedLedger;
+/// Build the behaviour with exactly the methods the replay serves,
+/// so the menu the peer sees matches `Recording::serve`.
+pub fn behaviour() -> libp2p_rpc_behaviour::Behaviour {
+    BehaviourBuilder::default()
+        .register_method::<GetBestTipV2>()
+        .register_method::<GetAncestryV2>()
+        .register_method::<GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>()
+        .register_method::<AnswerSyncLedgerQueryV2>()
+        .register_method::<GetTransitionChainV2>()
+        .register_method::<GetTransitionChainProofV1ForV2>()
+        .register_method::<GetTransitionKnowledgeV1ForV2>()
+        .register_method::<GetSomeInitialPeersV1ForV2>()
+        .build()
+}
 
-pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height: u32) {
-    let path_blocks = path_main.join("blocks");
-    let path = path_main.join(height.to_string());
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("the port of {addr} plus {offset} is out of range")]
+    PortOverflow { addr: Multiaddr, offset: usize },
+    #[error("{0}")]
+    Transport(#[from] TransportError),
+    #[error("cannot create the trace {}: {err}", path.display())]
+    Trace { path: PathBuf, err: io::Error },
+    #[error("cannot create the session {}: {err}", path.display())]
+    Session { path: PathBuf, err: io::Error },
+}
 
-    let mut file = File::open(path.join("best_tip")).unwrap();
-    let best_tip = <GetBestTipV2 as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+pub async fn run(
+    mut swarm: libp2p::Swarm<Behaviour>,
+    recording: &RefCell<Recording>,
+    trace: Option<&Path>,
+    session: Option<&Path>,
+    fault: FaultProfile,
+    initial_peers: &[Multiaddr],
+) -> Result<(), ReplayError> {
+    let mut session = session
+        .map(|path| {
+            SessionWriter::create(path).map_err(|err| ReplayError::Session {
+                path: path.to_owned(),
+                err,
+            })
+        })
+        .transpose()?;
+    let mut peer_exchange = PeerExchange::new(initial_peers.to_vec());
 
-    let mut file = File::open(path.join("ancestry")).unwrap();
-    let ancestry = <GetAncestryV2 as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+    let mut peers = BTreeMap::default();
+    let mut query_log = QueryLog::new(trace).map_err(|err| ReplayError::Trace {
+        path: trace.map(Path::to_owned).unwrap_or_default(),
+        err,
+    })?;
+    // the queries waiting for the fault delay, the swarm keeps running meanwhile
+    let mut delayed = FuturesUnordered::new();
 
-    let mut file = File::open(path.join("staged_ledger_aux")).unwrap();
-    type T = GetStagedLedgerAuxAndPendingCoinbasesAtHashV2;
-    let staged_ledger_aux = <T as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
 
-    let mut ledgers = BTreeMap::new();
-    for entry in fs::read_dir(path.join("ledgers")).unwrap() {
-        let entry = entry.unwrap();
-        let file = File::open(entry.path()).unwrap();
-        let ledger = SnarkedLedger::load_bin(file).unwrap();
-        ledgers.insert(entry.file_name().to_str().unwrap().to_string(), ledger);
+    loop {
+        let (peer_id, stream_id, header, bytes) = tokio::select! {
+            event = swarm.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                peer_exchange.on_swarm_event(&event);
+                match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        let address =
+                            mina_transport::with_peer_id(&address, *swarm.local_peer_id());
+                        log::info!("listen on {address}");
+                        continue;
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Rpc((
+                        peer_id,
+                        Event::ConnectionEstablished,
+                    ))) => {
+                        peers.insert(peer_id, 0);
+                        log::info!("new connection {peer_id}");
+                        continue;
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Rpc((
+                        peer_id,
+                        Event::ConnectionClosed,
+                    ))) => {
+                        log::info!("connection closed {peer_id}");
+                        peers.remove(&peer_id);
+                        continue;
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Rpc((
+                        peer_id,
+                        Event::Stream {
+                            stream_id,
+                            received,
+                        },
+                    ))) => match received {
+                        Received::HandshakeDone => {
+                            log::info!("new stream {peer_id} {stream_id:?}");
+                            continue;
+                        }
+                        Received::Menu(menu) => {
+                            log::info!("menu: {menu:?}");
+                            continue;
+                        }
+                        Received::Query { header, bytes } => {
+                            if fault.should_drop() {
+                                let tag = header.tag.to_string_lossy();
+                                log::info!("fault: drop {tag} from {peer_id}");
+                                continue;
+                            }
+                            if !fault.delay.is_zero() {
+                                let delay = fault.delay;
+                                delayed.push(async move {
+                                    tokio::time::sleep(delay).await;
+                                    (peer_id, stream_id, header, bytes)
+                                });
+                                continue;
+                            }
+                            (peer_id, stream_id, header, bytes)
+                        }
+                        _ => continue,
+                    },
+                    _ => continue,
+                }
+            }
+            Some(query) = delayed.next() => query,
+            _ = &mut ctrl_c => break,
+        };
+
+        let time = Instant::now();
+        let served = recording.borrow_mut().serve(
+            &mut swarm,
+            peer_id,
+            stream_id,
+            &header,
+            &bytes,
+            &peer_exchange,
+        );
+        let tag = header.tag.to_string_lossy();
+        let (query, response) = match served {
+            Ok(served) => served,
+            Err(err) => {
+                log::warn!("cannot decode the query {tag} of {peer_id}: {err}");
+                swarm.behaviour_mut().manager.ban(peer_id);
+                continue;
+            }
+        };
+        query_log.record(
+            peer_id,
+            &tag,
+            header.version,
+            header.id,
+            &query,
+            response.len(),
+            time.elapsed(),
+        );
+        if let Some(session) = &mut session {
+            session.record(peer_id, &tag, header.version, &bytes, &response);
+        }
+
+        let served = peers.entry(peer_id).or_default();
+        *served += 1;
+        if fault.should_disconnect(*served) {
+            log::info!("fault: disconnect {peer_id} after {served} queries");
+            let _ = swarm.disconnect_peer_id(peer_id);
+        }
+    }
+
+    query_log.summary();
+    Ok(())
+}
+
+/// Run several replay peers with independent identities, all built from `transport`.
+/// The peer `i` listens on the `listen` addresses with the port increased by `i`.
+/// Each peer advertises the others and the `initial_peers` in `get_some_initial_peers`.
+/// Every height is loaded once and shared by the peers that serve it.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_many(
+    keys: Vec<Keypair>,
+    transport: TransportConfig,
+    manager: &ConnectionManagerConfig,
+    listen: &[Multiaddr],
+    chain_id: &[u8],
+    path_main: &Path,
+    heights: &[u32],
+    trace: Option<PathBuf>,
+    session: Option<PathBuf>,
+    faults: &[FaultProfile],
+    initial_peers: &[Multiaddr],
+) -> Result<(), ReplayError> {
+    let listen = (0..keys.len())
+        .map(|i| {
+            listen
+                .iter()
+                .map(|addr| {
+                    offset_port(addr, i).ok_or_else(|| ReplayError::PortOverflow {
+                        addr: addr.clone(),
+                        offset: i,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let addrs = keys
+        .iter()
+        .zip(&listen)
+        .map(|(key, listen)| {
+            let peer_id = key.public().to_peer_id();
+            listen
+                .iter()
+                .map(|addr| mina_transport::with_peer_id(&localhost(addr), peer_id))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut recordings = BTreeMap::new();
+    for height in heights {
+        recordings
+            .entry(*height)
+            .or_insert_with(|| RefCell::new(Recording::load(path_main, *height)));
     }
 
-    let file = File::open(path_main.join("blocks").join("table.json")).unwrap();
-    let table = serde_json::from_reader::<_, BTreeMap<String, u32>>(file).unwrap();
+    let mut replays = vec![];
+    for (i, (key, listen)) in keys.into_iter().zip(listen).enumerate() {
+        let behaviour = Behaviour::new(behaviour(), manager.clone());
+        let swarm = transport
+            .clone()
+            .listen_on(listen)
+            .build(key, chain_id, behaviour)?;
+
+        let others = addrs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .flat_map(|(_, addrs)| addrs.iter().cloned())
+            .chain(initial_peers.iter().cloned())
+            .collect::<Vec<_>>();
+        let height = heights[i % heights.len()];
+        let recording = &recordings[&height];
+        let fault = faults.get(i).cloned().unwrap_or_default();
+        let trace = trace.as_ref().map(|path| indexed(path, i));
+        let session = session.as_ref().map(|path| indexed(path, i));
+        let addr = &addrs[i];
+        log::info!("peer {i}: {addr:?}, height: {height}, fault: {fault:?}");
+
+        replays.push(async move {
+            let (trace, session) = (trace.as_deref(), session.as_deref());
+            run(swarm, recording, trace, session, fault, &others).await
+        });
+    }
+    // a peer that cannot start stops the others
+    future::try_join_all(replays).await?;
 
-    let mut peers = BTreeSet::default();
+    Ok(())
+}
+
+/// The address with the TCP or UDP port increased by `offset`, `None` if it overflows.
+fn offset_port(addr: &Multiaddr, offset: usize) -> Option<Multiaddr> {
+    let offset = u16::try_from(offset).ok()?;
+    addr.iter()
+        .map(|protocol| match protocol {
+            Protocol::Tcp(port) => port.checked_add(offset).map(Protocol::Tcp),
+            Protocol::Udp(port) => port.checked_add(offset).map(Protocol::Udp),
+            protocol => Some(protocol),
+        })
+        .collect()
+}
 
-    while let Some(event) = swarm.next().await {
-        match event {
-            SwarmEvent::NewListenAddr { address, .. } => {
-                log::info!("listen on {address}");
+// the peers run on this host, `0.0.0.0` becomes `127.0.0.1`
+fn localhost(addr: &Multiaddr) -> Multiaddr {
+    addr.iter()
+        .map(|protocol| match protocol {
+            Protocol::Ip4(ip) if ip.is_unspecified() => Protocol::Ip4(Ipv4Addr::LOCALHOST),
+            Protocol::Ip6(ip) if ip.is_unspecified() => Protocol::Ip6(Ipv6Addr::LOCALHOST),
+            protocol => protocol,
+        })
+        .collect()
+}
+
+// `trace.jsonl` becomes `trace.0.jsonl`
+fn indexed(path: &Path, i: usize) -> PathBuf {
+    match path.extension() {
+        Some(ext) => path.with_extension(format!("{i}.{}", ext.to_string_lossy())),
+        None => path.with_extension(i.to_string()),
+    }
+}
+
+/// The data recorded by `record` at some height, enough to bootstrap a node.
+pub struct Recording {
+    path_blocks: PathBuf,
+    best_tip: <GetBestTipV2 as RpcMethod>::Response,
+    ancestry: <GetAncestryV2 as RpcMethod>::Response,
+    staged_ledger_aux: <GetStagedLedgerAuxAndPendingCoinbasesAtHashV2 as RpcMethod>::Response,
+    snarked_block_hash: Option<v2::StateHash>,
+    ledgers: BTreeMap<String, SnarkedLedger>,
+    table: BTreeMap<String, u32>,
+    // from the root to the best tip
+    transition_knowledge: Vec<v2::StateHash>,
+}
+
+impl Recording {
+    pub fn load(path_main: &Path, height: u32) -> Self {
+        let path_blocks = path_main.join("blocks");
+        let path = path_main.join(height.to_string());
+
+        let mut file = File::open(path.join("best_tip")).unwrap();
+        let best_tip = <GetBestTipV2 as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+
+        let mut file = File::open(path.join("ancestry")).unwrap();
+        let ancestry = <GetAncestryV2 as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+
+        let mut file = File::open(path.join("staged_ledger_aux")).unwrap();
+        type T = GetStagedLedgerAuxAndPendingCoinbasesAtHashV2;
+        let staged_ledger_aux = <T as RpcMethod>::Response::binprot_read(&mut file).unwrap();
+
+        let mut ledgers = BTreeMap::new();
+        for entry in fs::read_dir(path.join("ledgers")).unwrap() {
+            let entry = entry.unwrap();
+            let file = File::open(entry.path()).unwrap();
+            let ledger = SnarkedLedger::load_bin(file).unwrap();
+            ledgers.insert(entry.file_name().to_str().unwrap().to_string(), ledger);
+        }
+
+        let file = File::open(path_blocks.join("table.json")).unwrap();
+        let table = serde_json::from_reader::<_, BTreeMap<String, u32>>(file).unwrap();
+
+        let snarked_block_hash = best_tip.as_ref().map(|best_tip| {
+            let hash = best_tip.proof.1.header.protocol_state.hash();
+            v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash.inner().0.clone()))
+        });
+
+        let mut recording = Recording {
+            path_blocks,
+            best_tip,
+            ancestry,
+            staged_ledger_aux,
+            snarked_block_hash,
+            ledgers,
+            table,
+            transition_knowledge: vec![],
+        };
+        recording.transition_knowledge = recording.load_transition_knowledge();
+
+        recording
+    }
+
+    fn load_transition_knowledge(&self) -> Vec<v2::StateHash> {
+        let (Some(best_tip), Some(root)) = (&self.best_tip, &self.snarked_block_hash) else {
+            return vec![];
+        };
+        let head = best_tip.data.header.protocol_state.hash();
+        let head = v2::StateHash::from(v2::DataHashLibStateHashStableV1(head.inner().0.clone()));
+
+        let mut hashes = vec![head];
+        let mut last = best_tip.data.header.protocol_state.previous_state_hash.clone();
+        while last != *root {
+            let Some(block) = self.block(&last) else {
+                log::warn!("no block {last}, transition knowledge is incomplete");
+                break;
+            };
+            let previous = block.header.protocol_state.previous_state_hash.clone();
+            hashes.push(last);
+            last = previous;
+        }
+        hashes.reverse();
+
+        hashes
+    }
+
+    fn block(&self, hash: &v2::StateHash) -> Option<v2::MinaBlockBlockStableV2> {
+        let height = self.table.get(&hash.to_string())?;
+        let path = self.path_blocks.join(height.to_string()).join(hash.to_string());
+        let mut file = File::open(path).ok()?;
+        v2::MinaBlockBlockStableV2::binprot_read(&mut file).ok()
+    }
+
+    /// Answer the query, return a summary of the query and the encoded response.
+    /// Any method the recording cannot serve is answered with an `unimplemented` error,
+    /// so the peer never waits for the response forever.
+    /// Fails if the query cannot be decoded, then nothing is sent.
+    pub fn serve(
+        &mut self,
+        swarm: &mut libp2p::Swarm<Behaviour>,
+        peer_id: PeerId,
+        stream_id: StreamId,
+        header: &QueryHeader,
+        bytes: &[u8],
+        peer_exchange: &PeerExchange,
+    ) -> Result<(serde_json::Value, Vec<u8>), binprot::Error> {
+        let QueryHeader { tag, version, id } = header;
+        let (version, id) = (*version, *id);
+        let mut bytes = bytes;
+        let tag_str = std::str::from_utf8(tag.as_ref()).unwrap_or_default();
+        log::info!("handling {tag_str}, {}", version);
+        let served = match (tag_str, version) {
+            (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
+                let response = respond::<GetBestTipV2>(
+                    swarm,
+                    peer_id,
+                    stream_id,
+                    id,
+                    self.best_tip.clone(),
+                );
+                // the query is `()`
+                (serde_json::Value::Null, response)
             }
-            SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
-                peers.insert(peer_id);
-                log::info!("new connection {peer_id}");
+            (GetAncestryV2::NAME, GetAncestryV2::VERSION) => {
+                type T = GetAncestryV2;
+                let query = QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+                let hash = v2::StateHash::from(query.hash);
+
+                let response = respond::<T>(swarm, peer_id, stream_id, id, self.ancestry.clone());
+                (serde_json::json!({ "state_hash": hash.to_string() }), response)
             }
-            SwarmEvent::Behaviour((peer_id, Event::ConnectionClosed)) => {
-                log::info!("connection closed {peer_id}");
-                peers.remove(&peer_id);
+            (AnswerSyncLedgerQueryV2::NAME, AnswerSyncLedgerQueryV2::VERSION) => {
+                type T = AnswerSyncLedgerQueryV2;
+                let (hash, query) =
+                    QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+
+                let hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash));
+                let hash_str = match serde_json::to_value(&hash).unwrap() {
+                    serde_json::Value::String(s) => s,
+                    _ => panic!(),
+                };
+                let summary = serde_json::json!({
+                    "ledger_hash": hash_str,
+                    "query": query,
+                });
+
+                let response = match self.ledgers.get_mut(&hash_str) {
+                    Some(ledger) => Ok(ledger.serve_query(query)),
+                    None => {
+                        log::warn!("no ledger {hash_str}");
+                        let msg = format!("ledger {hash_str} is not in the recording");
+                        Err(Info::CouldNotConstruct(msg.as_str().into()))
+                    }
+                };
+
+                let response = respond::<T>(swarm, peer_id, stream_id, id, RpcResult(response));
+                (summary, response)
             }
-            SwarmEvent::Behaviour((
-                peer_id,
-                Event::Stream {
-                    stream_id,
-                    received,
-                },
-            )) => match received {
-                Received::HandshakeDone => {
-                    log::info!("new stream {peer_id} {stream_id:?}");
-                }
-                Received::Menu(menu) => {
-                    log::info!("menu: {menu:?}");
-                }
-                Received::Query {
-                    header: QueryHeader { tag, version, id },
-                    bytes,
-                } => {
-                    let mut bytes = bytes.as_slice();
-                    let tag = std::str::from_utf8(tag.as_ref()).unwrap();
-                    log::info!("handling {tag}, {}", version);
-                    match (tag, version) {
-                        (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
-                            swarm
-                                .behaviour_mut()
-                                .respond::<GetBestTipV2>(
-                                    peer_id,
-                                    stream_id,
-                                    id,
-                                    Ok(best_tip.clone()),
-                                )
-                                .unwrap();
-                        }
-                        (GetAncestryV2::NAME, GetAncestryV2::VERSION) => {
-                            swarm
-                                .behaviour_mut()
-                                .respond::<GetAncestryV2>(
-                                    peer_id,
-                                    stream_id,
-                                    id,
-                                    Ok(ancestry.clone()),
-                                )
-                                .unwrap();
-                        }
-                        (AnswerSyncLedgerQueryV2::NAME, AnswerSyncLedgerQueryV2::VERSION) => {
-                            type T = AnswerSyncLedgerQueryV2;
-                            let (hash, query) =
-                                QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)
-                                    .unwrap()
-                                    .0;
-
-                            let hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash));
-                            let hash_str = match serde_json::to_value(&hash).unwrap() {
-                                serde_json::Value::String(s) => s,
-                                _ => panic!(),
-                            };
-
-                            let ledger = ledgers.get_mut(&hash_str).unwrap();
-                            let response = ledger.serve_query(query);
-
-                            swarm
-                                .behaviour_mut()
-                                .respond::<T>(peer_id, stream_id, id, Ok(RpcResult(Ok(response))))
-                                .unwrap();
-                        }
-                        (
-                            GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::NAME,
-                            GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
-                        ) => swarm
-                            .behaviour_mut()
-                            .respond::<GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(
-                                peer_id,
-                                stream_id,
-                                id,
-                                Ok(staged_ledger_aux.clone()),
-                            )
-                            .unwrap(),
-                        (GetTransitionChainV2::NAME, GetTransitionChainV2::VERSION) => {
-                            type T = GetTransitionChainV2;
-                            let hashes =
-                                QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)
-                                    .unwrap()
-                                    .0;
-
-                            // let mut contains_last = false;
-                            let response = hashes
-                                .into_iter()
-                                .map(|hash| {
-                                    let hash =
-                                        v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash));
-                                    // if hash
-                                    //     == best_tip
-                                    //         .as_ref()
-                                    //         .unwrap()
-                                    //         .data
-                                    //         .header
-                                    //         .protocol_state
-                                    //         .previous_state_hash
-                                    // {
-                                    //     contains_last = true;
-                                    // }
-                                    let height = table.get(&hash.to_string()).unwrap();
-                                    let path =
-                                        path_blocks.join(height.to_string()).join(hash.to_string());
-                                    let mut file = File::open(path).unwrap();
-                                    binprot::BinProtRead::binprot_read(&mut file).unwrap()
-                                })
-                                .collect();
-                            swarm
-                                .behaviour_mut()
-                                .respond::<T>(peer_id, stream_id, id, Ok(Some(response)))
-                                .unwrap();
-                            // if contains_last {
-                            //     swarm.disconnect_peer_id(peer_id).unwrap();
-                            // }
-                        }
-                        (
-                            GetTransitionChainProofV1ForV2::NAME,
-                            GetTransitionChainProofV1ForV2::VERSION,
-                        ) => {
-                            type T = GetTransitionChainProofV1ForV2;
-                            let hash =
-                                QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)
-                                    .unwrap()
-                                    .0;
-
-                            let hash = v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash));
-                            let response = if let Some(height) = table.get(&hash.to_string()) {
-                                let path = path_blocks
-                                    .join(height.to_string())
-                                    .join(format!("proof_{hash}"));
-                                let mut file = File::open(path).unwrap();
-                                binprot::BinProtRead::binprot_read(&mut file).unwrap()
-                            } else {
-                                log::warn!("no proof for block {hash}");
-                                None
-                            };
-
-                            swarm
-                                .behaviour_mut()
-                                .respond::<T>(peer_id, stream_id, id, Ok(response))
-                                .unwrap();
-                        }
-                        (name, version) => {
-                            log::warn!("TODO: unhandled {name}, {version}");
+            (
+                GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::NAME,
+                GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
+            ) => {
+                type T = GetStagedLedgerAuxAndPendingCoinbasesAtHashV2;
+                let hash = QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+                let hash = v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash));
+
+                let response = if self.snarked_block_hash.as_ref() == Some(&hash) {
+                    self.staged_ledger_aux.clone()
+                } else {
+                    log::warn!("no staged ledger aux at {hash}");
+                    None
+                };
+                let response = respond::<T>(swarm, peer_id, stream_id, id, response);
+                (serde_json::json!({ "state_hash": hash.to_string() }), response)
+            }
+            (GetTransitionChainV2::NAME, GetTransitionChainV2::VERSION) => {
+                type T = GetTransitionChainV2;
+                let hashes = QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+
+                let hashes = hashes
+                    .into_iter()
+                    .map(|hash| v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash)))
+                    .collect::<Vec<_>>();
+                let summary = serde_json::json!({
+                    "state_hashes": hashes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                });
+
+                // let mut contains_last = false;
+                // the peer expects all the blocks or nothing
+                let response = hashes
+                    .iter()
+                    .map(|hash| {
+                        // if hash
+                        //     == best_tip
+                        //         .as_ref()
+                        //         .unwrap()
+                        //         .data
+                        //         .header
+                        //         .protocol_state
+                        //         .previous_state_hash
+                        // {
+                        //     contains_last = true;
+                        // }
+                        let block = self.block(hash);
+                        if block.is_none() {
+                            log::warn!("no block {hash}");
                         }
-                    }
-                }
-                _ => {}
-            },
-            _ => {}
+                        block
+                    })
+                    .collect::<Option<Vec<_>>>();
+                let response = respond::<T>(swarm, peer_id, stream_id, id, response);
+                // if contains_last {
+                //     swarm.disconnect_peer_id(peer_id).unwrap();
+                // }
+                (summary, response)
+            }
+            (GetTransitionChainProofV1ForV2::NAME, GetTransitionChainProofV1ForV2::VERSION) => {
+                type T = GetTransitionChainProofV1ForV2;
+                let hash = QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+
+                let hash = v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash));
+                let proof = self.table.get(&hash.to_string()).and_then(|height| {
+                    let path = self
+                        .path_blocks
+                        .join(height.to_string())
+                        .join(format!("proof_{hash}"));
+                    let mut file = File::open(path).ok()?;
+                    <T as RpcMethod>::Response::binprot_read(&mut file).ok()
+                });
+                let response = proof.unwrap_or_else(|| {
+                    log::warn!("no proof for block {hash}");
+                    None
+                });
+
+                let response = respond::<T>(swarm, peer_id, stream_id, id, response);
+                (serde_json::json!({ "state_hash": hash.to_string() }), response)
+            }
+            (GetTransitionKnowledgeV1ForV2::NAME, GetTransitionKnowledgeV1ForV2::VERSION) => {
+                type T = GetTransitionKnowledgeV1ForV2;
+                let response = self
+                    .transition_knowledge
+                    .iter()
+                    .map(|hash| hash.0.clone())
+                    .collect();
+                let response = respond::<T>(swarm, peer_id, stream_id, id, response);
+                // the query is `()`
+                (serde_json::Value::Null, response)
+            }
+            (GetSomeInitialPeersV1ForV2::NAME, GetSomeInitialPeersV1ForV2::VERSION) => {
+                let response = respond::<GetSomeInitialPeersV1ForV2>(
+                    swarm,
+                    peer_id,
+                    stream_id,
+                    id,
+                    peer_exchange.peers(peer_id),
+                );
+                // the query is `()`
+                (serde_json::Value::Null, response)
+            }
+            // the recording has the v2 types only, the v1 methods are not served either
+            (name, version) => {
+                log::warn!("unimplemented {name}, {version}");
+                respond_unimplemented(swarm, peer_id, stream_id, header);
+                (serde_json::Value::Null, vec![])
+            }
+        };
+
+        Ok(served)
+    }
+}
+
+/// The response encoded in advance, written to the stream as it is.
+/// So the response is encoded once, for the peer, the statistics and the session.
+#[derive(Debug, Clone)]
+struct Encoded(Vec<u8>);
+
+impl BinProtWrite for Encoded {
+    fn binprot_write<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.0)
+    }
+}
+
+impl BinProtRead for Encoded {
+    fn binprot_read<R: io::Read + ?Sized>(r: &mut R) -> Result<Self, binprot::Error>
+    where
+        Self: Sized,
+    {
+        let mut bytes = vec![];
+        r.read_to_end(&mut bytes)?;
+        Ok(Encoded(bytes))
+    }
+}
+
+/// The method `M` with the response that is already encoded.
+struct PreEncoded<M>(PhantomData<M>);
+
+impl<M: RpcMethod> RpcMethod for PreEncoded<M> {
+    const NAME: &'static str = M::NAME;
+    const VERSION: i32 = M::VERSION;
+    type Query = M::Query;
+    type Response = Encoded;
+}
+
+/// Send the response and return it encoded.
+fn respond<M: RpcMethod>(
+    swarm: &mut libp2p::Swarm<Behaviour>,
+    peer_id: PeerId,
+    stream_id: StreamId,
+    id: i64,
+    response: M::Response,
+) -> Vec<u8> {
+    let mut bytes = vec![];
+    response.binprot_write(&mut bytes).unwrap();
+    let response = Encoded(bytes);
+    swarm
+        .behaviour_mut()
+        .rpc
+        .respond::<PreEncoded<M>>(peer_id, stream_id, id, Ok(response.clone()))
+        .unwrap();
+    response.0
+}
+
+/// Tell the peer the method of the query is not served.
+fn respond_unimplemented(
+    swarm: &mut libp2p::Swarm<Behaviour>,
+    peer_id: PeerId,
+    stream_id: StreamId,
+    header: &QueryHeader,
+) {
+    let err = rpc::unimplemented(header);
+    swarm
+        .behaviour_mut()
+        .rpc
+        .respond::<rpc::Unimplemented>(peer_id, stream_id, header.id, Err(err))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap, path::PathBuf};
+
+    use libp2p::{Multiaddr, PeerId};
+    use mina_p2p_messages::rpc::{GetBestTipV2, GetSomeInitialPeersV1ForV2, GetTransitionChainV2};
+    use mina_transport::ConnectionManagerConfig;
+
+    use super::{behaviour, run, FaultProfile, Recording};
+    use crate::{behaviour::Behaviour, client::Client};
+
+    const CHAIN_ID: &[u8] = b"667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db";
+
+    // a recording of nothing, the replay answers every query with an empty response
+    fn empty_recording() -> Recording {
+        Recording {
+            path_blocks: PathBuf::new(),
+            best_tip: None,
+            ancestry: None,
+            staged_ledger_aux: None,
+            snarked_block_hash: None,
+            ledgers: BTreeMap::new(),
+            table: BTreeMap::new(),
+            transition_knowledge: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn record_client_queries_replay_in_memory() {
+        let server_key = mina_transport::generate_identity();
+        let server_peer_id = server_key.public().to_peer_id();
+        let listen = "/memory/18302".parse::<Multiaddr>().unwrap();
+        let behaviour = Behaviour::new(behaviour(), ConnectionManagerConfig::default());
+        let server =
+            mina_transport::memory_swarm(server_key, CHAIN_ID, [listen], [], behaviour).unwrap();
+        let recording = RefCell::new(empty_recording());
+        let advertised_peer_id = PeerId::random();
+        let advertised = format!("/ip4/10.0.0.1/tcp/8302/p2p/{advertised_peer_id}")
+            .parse::<Multiaddr>()
+            .unwrap();
+
+        let client_key = mina_transport::generate_identity();
+        let server_addr = format!("/memory/18302/p2p/{server_peer_id}")
+            .parse()
+            .unwrap();
+        let behaviour = Behaviour::new(Client::behaviour(), ConnectionManagerConfig::default());
+        let swarm =
+            mina_transport::memory_swarm(client_key, CHAIN_ID, [], [server_addr], behaviour)
+                .unwrap();
+        let mut client = Client::new(swarm, vec![]);
+
+        let client = async move {
+            let best_tip = client.rpc::<GetBestTipV2>(()).await.unwrap();
+            assert!(best_tip.is_none());
+
+            let blocks = client.rpc::<GetTransitionChainV2>(vec![]).await.unwrap();
+            assert_eq!(blocks.map(|blocks| blocks.len()), Some(0));
+
+            let peers = client.rpc::<GetSomeInitialPeersV1ForV2>(()).await.unwrap();
+            assert_eq!(peers.len(), 1);
+            let peer_id = advertised_peer_id.to_string();
+            assert_eq!(peers[0].peer_id.0.as_ref(), peer_id.as_bytes());
+        };
+
+        let fault = FaultProfile::default();
+        tokio::select! {
+            _ = run(server, &recording, None, None, fault, &[advertised]) => {
+                panic!("the replay stopped before the client is done")
+            }
+            _ = client => {}
         }
     }
 }
@@ -0,0 +1,171 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use binprot::{BinProtRead, BinProtWrite};
+use libp2p::PeerId;
+use mina_p2p_messages::{
+    rpc::{
+        GetBestTipV2, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
+        AnswerSyncLedgerQueryV2, GetTransitionChainV2, GetTransitionChainProofV1ForV2,
+        GetTransitionKnowledgeV1ForV2, GetSomeInitialPeersV1ForV2,
+    },
+    rpc_kernel::{RpcMethod, QueryPayload},
+};
+use serde::{Serialize, Deserialize};
+
+use super::client::{Client, ClientError};
+
+/// A query received by the replay, and the response the replay gave.
+/// The session file holds one entry per line, in JSON.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the replay started.
+    pub time_ms: u64,
+    pub peer_id: String,
+    pub tag: String,
+    pub version: i32,
+    /// Hex encoded query payload, as it was on the wire.
+    pub query: String,
+    /// Hex encoded binprot of the response.
+    pub response: String,
+}
+
+pub struct SessionWriter {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl SessionWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(SessionWriter {
+            file: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        peer_id: PeerId,
+        tag: &str,
+        version: i32,
+        query: &[u8],
+        response: &[u8],
+    ) {
+        let entry = Entry {
+            time_ms: self.start.elapsed().as_millis() as u64,
+            peer_id: peer_id.to_string(),
+            tag: tag.to_owned(),
+            version,
+            query: hex::encode(query),
+            response: hex::encode(response),
+        };
+        let res = serde_json::to_writer(&mut self.file, &entry)
+            .map_err(io::Error::from)
+            .and_then(|()| self.file.write_all(b"\n"))
+            .and_then(|()| self.file.flush());
+        if let Err(err) = res {
+            log::error!("failed to write the session: {err}");
+        }
+    }
+}
+
+/// Act as the client, issue the recorded queries with the recorded timing
+/// against the server the `client` is connected to, and compare the answers.
+/// The queries are issued one by one, even if the node sent some concurrently.
+pub async fn replay(client: &mut Client, path: &Path, no_delay: bool) {
+    let file = File::open(path).unwrap();
+    let entries = BufReader::new(file)
+        .lines()
+        .map(|line| serde_json::from_str::<Entry>(&line.unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    let first = entries.first().map(|entry| entry.time_ms).unwrap_or_default();
+
+    let start = Instant::now();
+    let (mut matched, mut mismatched, mut failed) = (0, 0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        if !no_delay {
+            // a hand-edited session may be out of order, such an entry goes immediately
+            let offset = Duration::from_millis(entry.time_ms.saturating_sub(first));
+            if let Some(wait) = offset.checked_sub(start.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let query = hex::decode(&entry.query).unwrap();
+        let expected = hex::decode(&entry.response).unwrap();
+        let res = match (entry.tag.as_str(), entry.version) {
+            (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
+                reissue::<GetBestTipV2>(client, &query, &expected).await
+            }
+            (GetAncestryV2::NAME, GetAncestryV2::VERSION) => {
+                reissue::<GetAncestryV2>(client, &query, &expected).await
+            }
+            (AnswerSyncLedgerQueryV2::NAME, AnswerSyncLedgerQueryV2::VERSION) => {
+                reissue::<AnswerSyncLedgerQueryV2>(client, &query, &expected).await
+            }
+            (
+                GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::NAME,
+                GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
+            ) => {
+                type T = GetStagedLedgerAuxAndPendingCoinbasesAtHashV2;
+                reissue::<T>(client, &query, &expected).await
+            }
+            (GetTransitionChainV2::NAME, GetTransitionChainV2::VERSION) => {
+                reissue::<GetTransitionChainV2>(client, &query, &expected).await
+            }
+            (GetTransitionChainProofV1ForV2::NAME, GetTransitionChainProofV1ForV2::VERSION) => {
+                reissue::<GetTransitionChainProofV1ForV2>(client, &query, &expected).await
+            }
+            (GetTransitionKnowledgeV1ForV2::NAME, GetTransitionKnowledgeV1ForV2::VERSION) => {
+                reissue::<GetTransitionKnowledgeV1ForV2>(client, &query, &expected).await
+            }
+            (GetSomeInitialPeersV1ForV2::NAME, GetSomeInitialPeersV1ForV2::VERSION) => {
+                // the answer depends on the topology, not on the recording
+                log::info!("{i}: skip {}, {}", entry.tag, entry.version);
+                continue;
+            }
+            (tag, version) => {
+                log::warn!("{i}: unknown method {tag}, {version}");
+                continue;
+            }
+        };
+        match res {
+            Ok(true) => {
+                log::debug!("{i}: {} matched", entry.tag);
+                matched += 1;
+            }
+            Ok(false) => {
+                log::warn!("{i}: {} mismatched, query {}", entry.tag, entry.query);
+                mismatched += 1;
+            }
+            Err(err) => {
+                log::error!("{i}: {} failed: {err}", entry.tag);
+                failed += 1;
+            }
+        }
+    }
+
+    log::info!("matched: {matched}, mismatched: {mismatched}, failed: {failed}");
+}
+
+async fn reissue<M>(
+    client: &mut Client,
+    mut query: &[u8],
+    expected: &[u8],
+) -> Result<bool, ClientError>
+where
+    M: RpcMethod,
+{
+    let query = QueryPayload::<M::Query>::binprot_read(&mut query)?.0;
+    let response = client.rpc::<M>(query).await?;
+    let mut bytes = vec![];
+    response
+        .binprot_write(&mut bytes)
+        .expect("writing to vector cannot fail");
+
+    Ok(bytes == expected)
+}
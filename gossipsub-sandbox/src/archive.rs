@@ -0,0 +1,423 @@
+//! A live archive of the chain in the layout of bootstrap-sandbox:
+//! `blocks/<height>/<state_hash>`, `blocks/<height>/proof_<state_hash>` and `blocks/table.json`.
+//! The blocks come from gossip, their missing ancestors are fetched with `get_transition_chain`,
+//! the proofs with `get_transition_chain_proof`.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use binprot::{BinProtRead, BinProtWrite};
+use libp2p::{
+    gossipsub::{self, MessageAcceptance},
+    PeerId,
+};
+use libp2p_rpc_behaviour::{Behaviour, BehaviourBuilder, Event, Received, StreamId};
+use mina_p2p_messages::{
+    gossip::GossipNetMessageV2,
+    rpc::{
+        GetBestTipV2, GetSomeInitialPeersV1ForV2, GetTransitionChainProofV1ForV2,
+        GetTransitionChainV2,
+    },
+    rpc_kernel::{QueryHeader, ResponseHeader, ResponsePayload, RpcMethod},
+    v2,
+};
+use tokio::time::Instant;
+
+use super::{
+    message::Kind,
+    node::{self, Node},
+    validate::Validator,
+};
+
+/// Give up on the block after asking this many peers.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Ask another peer if the answer does not come in this time.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `table.json` is rewritten at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The behaviour answers the methods a Mina peer asks first,
+/// so the peer keeps the connection.
+pub fn behaviour() -> Behaviour {
+    BehaviourBuilder::default()
+        .register_method::<GetBestTipV2>()
+        .register_method::<GetSomeInitialPeersV1ForV2>()
+        .build()
+}
+
+fn state_hash(block: &v2::MinaBlockBlockStableV2) -> v2::StateHash {
+    let hash = block.header.protocol_state.hash();
+    v2::StateHash::from(v2::DataHashLibStateHashStableV1(hash.inner().0.clone()))
+}
+
+fn height(block: &v2::MinaBlockBlockStableV2) -> u32 {
+    block
+        .header
+        .protocol_state
+        .body
+        .consensus_state
+        .blockchain_length
+        .as_u32()
+}
+
+/// What to fetch for the block.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Wanted {
+    Block,
+    Proof,
+}
+
+struct Archive {
+    path_blocks: PathBuf,
+    /// State hash to height, the same as bootstrap-sandbox writes.
+    table: BTreeMap<String, u32>,
+    /// The table has blocks that are not in `table.json` yet.
+    unsaved: bool,
+    depth: u32,
+    best_height: u32,
+    /// What to fetch, with the number of attempts.
+    wanted: VecDeque<(Wanted, v2::StateHash, usize)>,
+    /// The streams ready for queries.
+    streams: BTreeMap<PeerId, StreamId>,
+    /// The queries in flight, with the deadline of the answer.
+    pending: BTreeMap<(PeerId, i64), (Wanted, v2::StateHash, usize, Instant)>,
+    next_id: i64,
+}
+
+impl Archive {
+    fn new(path: &Path, depth: u32) -> Self {
+        let path_blocks = path.join("blocks");
+        fs::create_dir_all(&path_blocks).unwrap();
+        let table = match File::open(path_blocks.join("table.json")) {
+            Ok(f) => serde_json::from_reader(f).unwrap(),
+            Err(_) => BTreeMap::<String, u32>::new(),
+        };
+        let best_height = table.values().copied().max().unwrap_or_default();
+        Archive {
+            path_blocks,
+            table,
+            unsaved: false,
+            depth,
+            best_height,
+            wanted: VecDeque::new(),
+            streams: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    fn is_wanted(&self, what: Wanted, hash: &v2::StateHash) -> bool {
+        self.wanted.iter().any(|(w, h, _)| *w == what && h == hash)
+            || self
+                .pending
+                .values()
+                .any(|(w, h, ..)| *w == what && h == hash)
+    }
+
+    /// Store the block, fetch its proof and remember its parent if it is missing.
+    fn store(&mut self, block: &v2::MinaBlockBlockStableV2) {
+        let hash = state_hash(block);
+        let height = height(block);
+        if self.table.contains_key(&hash.to_string()) {
+            return;
+        }
+
+        let dir = self.path_blocks.join(height.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = File::create(dir.join(hash.to_string())).unwrap();
+        block.binprot_write(&mut file).unwrap();
+        self.table.insert(hash.to_string(), height);
+        self.unsaved = true;
+        log::info!("stored block {height} {hash}");
+        if !dir.join(format!("proof_{hash}")).exists() {
+            self.wanted.push_back((Wanted::Proof, hash, 0));
+        }
+
+        self.best_height = self.best_height.max(height);
+        let previous = block.header.protocol_state.previous_state_hash.clone();
+        let min_height = self.best_height.saturating_sub(self.depth);
+        if height > min_height.max(1)
+            && !self.table.contains_key(&previous.to_string())
+            && !self.is_wanted(Wanted::Block, &previous)
+        {
+            self.wanted.push_back((Wanted::Block, previous, 0));
+        }
+    }
+
+    fn store_proof(
+        &mut self,
+        hash: &v2::StateHash,
+        proof: &<GetTransitionChainProofV1ForV2 as RpcMethod>::Response,
+    ) {
+        let Some(height) = self.table.get(&hash.to_string()) else {
+            return;
+        };
+        let path = self
+            .path_blocks
+            .join(height.to_string())
+            .join(format!("proof_{hash}"));
+        let mut file = File::create(path).unwrap();
+        proof.binprot_write(&mut file).unwrap();
+        log::debug!("stored the proof of {hash}");
+    }
+
+    /// Write `table.json` if it changed, through a temporary file,
+    /// so it is complete even if the archive is killed while writing.
+    fn save(&mut self) -> io::Result<()> {
+        if !self.unsaved {
+            return Ok(());
+        }
+        let path = self.path_blocks.join("table.json");
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&tmp)?, &self.table)?;
+        fs::rename(tmp, path)?;
+        self.unsaved = false;
+        Ok(())
+    }
+
+    /// Ask for the wanted blocks, each on a stream that has nothing in flight.
+    fn request(&mut self, node: &mut Node) {
+        let Some(rpc) = node.swarm.behaviour_mut().rpc.as_mut() else {
+            return;
+        };
+        let idle = self
+            .streams
+            .iter()
+            .filter(|(peer_id, _)| !self.pending.keys().any(|(p, _)| p == *peer_id))
+            .map(|(peer_id, stream_id)| (*peer_id, *stream_id))
+            .collect::<Vec<_>>();
+        for (peer_id, stream_id) in idle {
+            let Some((what, hash, attempts)) = self.wanted.pop_front() else {
+                break;
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            let res = match what {
+                Wanted::Block => {
+                    rpc.query::<GetTransitionChainV2>(peer_id, stream_id, id, vec![hash.0.clone()])
+                }
+                Wanted::Proof => rpc.query::<GetTransitionChainProofV1ForV2>(
+                    peer_id,
+                    stream_id,
+                    id,
+                    hash.0.clone(),
+                ),
+            };
+            match res {
+                Ok(()) => {
+                    log::debug!("fetching {hash} from {peer_id}");
+                    let deadline = Instant::now() + QUERY_TIMEOUT;
+                    self.pending
+                        .insert((peer_id, id), (what, hash, attempts + 1, deadline));
+                }
+                Err(err) => {
+                    log::warn!("cannot query {peer_id}: {err}");
+                    self.wanted.push_front((what, hash, attempts));
+                }
+            }
+        }
+    }
+
+    /// Put the block back into the queue, unless it was asked too many times.
+    fn retry(&mut self, what: Wanted, hash: v2::StateHash, attempts: usize) {
+        if attempts < MAX_ATTEMPTS {
+            self.wanted.push_back((what, hash, attempts));
+        } else {
+            log::warn!("giving up on {hash}");
+        }
+    }
+
+    /// The earliest deadline of the queries in flight.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(.., deadline)| *deadline).min()
+    }
+
+    /// Retry the queries not answered in time, a late answer is ignored.
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, (.., deadline))| *deadline <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            let (what, hash, attempts, _) = self
+                .pending
+                .remove(&key)
+                .expect("the key is taken from the map");
+            log::warn!("{} did not answer for {hash} in time", key.0);
+            self.retry(what, hash, attempts);
+        }
+    }
+
+    fn on_rpc(&mut self, node: &mut Node, peer_id: PeerId, event: Event) {
+        match event {
+            Event::ConnectionEstablished => {
+                if let Some(rpc) = node.swarm.behaviour_mut().rpc.as_mut() {
+                    rpc.open(peer_id, 0);
+                }
+            }
+            Event::ConnectionClosed => {
+                self.streams.remove(&peer_id);
+                let lost = self
+                    .pending
+                    .keys()
+                    .filter(|(p, _)| *p == peer_id)
+                    .copied()
+                    .collect::<Vec<_>>();
+                for key in lost {
+                    let (what, hash, attempts, _) = self
+                        .pending
+                        .remove(&key)
+                        .expect("the key is taken from the map");
+                    self.retry(what, hash, attempts);
+                }
+            }
+            Event::Stream {
+                stream_id,
+                received,
+            } => match received {
+                Received::HandshakeDone => {
+                    self.streams.insert(peer_id, stream_id);
+                }
+                Received::Menu(_) => {}
+                Received::Query { header, bytes: _ } => {
+                    respond(node, peer_id, stream_id, &header);
+                }
+                Received::Response {
+                    header: ResponseHeader { id },
+                    bytes,
+                } => {
+                    let Some((what, hash, attempts, _)) = self.pending.remove(&(peer_id, id))
+                    else {
+                        return;
+                    };
+                    let mut bytes = bytes.as_slice();
+                    match what {
+                        Wanted::Block => {
+                            type T = GetTransitionChainV2;
+                            let blocks = decode::<T>(peer_id, &hash, &mut bytes)
+                                .and_then(|response| response.0);
+                            match blocks.and_then(|blocks| blocks.into_iter().next()) {
+                                Some(block) if state_hash(&block) == hash => self.store(&block),
+                                Some(_) => {
+                                    log::warn!("{peer_id} sent a wrong block for {hash}");
+                                    self.retry(what, hash, attempts);
+                                }
+                                None => self.retry(what, hash, attempts),
+                            }
+                        }
+                        Wanted::Proof => {
+                            type T = GetTransitionChainProofV1ForV2;
+                            match decode::<T>(peer_id, &hash, &mut bytes) {
+                                Some(proof @ Some(_)) => self.store_proof(&hash, &proof),
+                                _ => self.retry(what, hash, attempts),
+                            }
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// The response of the peer, `None` if it failed or does not decode.
+fn decode<M: RpcMethod>(
+    peer_id: PeerId,
+    hash: &v2::StateHash,
+    bytes: &mut &[u8],
+) -> Option<M::Response> {
+    match ResponsePayload::<M::Response>::binprot_read(bytes) {
+        Ok(payload) => match payload.0 {
+            Ok(response) => Some(response.0),
+            Err(err) => {
+                log::warn!("{peer_id} failed {} for {hash}: {err:?}", M::NAME);
+                None
+            }
+        },
+        Err(err) => {
+            log::warn!("cannot decode the response of {peer_id}: {err}");
+            None
+        }
+    }
+}
+
+/// Answer what a Mina peer asks right after connecting, the rest is unimplemented.
+fn respond(node: &mut Node, peer_id: PeerId, stream_id: StreamId, header: &QueryHeader) {
+    let Some(rpc) = node.swarm.behaviour_mut().rpc.as_mut() else {
+        return;
+    };
+    let QueryHeader { tag, version, id } = header;
+    let tag_str = std::str::from_utf8(tag.as_ref()).unwrap_or_default();
+    let res = match (tag_str, *version) {
+        (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
+            rpc.respond::<GetBestTipV2>(peer_id, stream_id, *id, Ok(None))
+        }
+        (GetSomeInitialPeersV1ForV2::NAME, GetSomeInitialPeersV1ForV2::VERSION) => {
+            rpc.respond::<GetSomeInitialPeersV1ForV2>(peer_id, stream_id, *id, Ok(vec![]))
+        }
+        _ => {
+            let err = mina_transport::rpc::unimplemented(header);
+            rpc.respond::<mina_transport::rpc::Unimplemented>(peer_id, stream_id, *id, Err(err))
+        }
+    };
+    if let Err(err) = res {
+        log::warn!("cannot respond to {peer_id}: {err}");
+    }
+}
+
+/// Archive until killed. Only the gossip blocks the validator accepts are stored,
+/// the ancestors must have the hash the child block names.
+pub async fn run(node: &mut Node, path: PathBuf, depth: u32, genesis_state_hash: Option<String>) {
+    let mut archive = Archive::new(&path, depth);
+    // only the blocks are validated, the snark fee does not matter
+    let mut validator = Validator::new(u64::MAX, genesis_state_hash);
+    log::info!(
+        "archiving into {}, {} blocks already there",
+        archive.path_blocks.display(),
+        archive.table.len(),
+    );
+    let mut next_save = Instant::now() + SAVE_INTERVAL;
+    loop {
+        let deadline = archive
+            .next_deadline()
+            .map_or(next_save, |d| d.min(next_save));
+        match node.next(Some(deadline)).await {
+            Some(node::Event::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            })) => {
+                // the other kinds are not archived, no need to decode them
+                if Kind::of_frame(&message.data) == Some(Kind::NewState) {
+                    let (acceptance, msg) = validator.validate(propagation_source, &message.data);
+                    if let (MessageAcceptance::Accept, Some(GossipNetMessageV2::NewState(block))) =
+                        (acceptance, msg)
+                    {
+                        archive.store(&block);
+                    }
+                }
+            }
+            Some(node::Event::Rpc(peer_id, event)) => archive.on_rpc(node, peer_id, event),
+            Some(_) => {}
+            None => {
+                let now = Instant::now();
+                archive.expire(now);
+                if now >= next_save {
+                    if let Err(err) = archive.save() {
+                        log::error!("cannot write the table: {err}");
+                    }
+                    next_save = now + SAVE_INTERVAL;
+                }
+            }
+        }
+        archive.request(node);
+    }
+}
@@ -0,0 +1,169 @@
+//! The gossipsub parameters. The defaults are the ones of the Mina daemon,
+//! its libp2p_helper runs go-libp2p-pubsub with the default mesh parameters
+//! and identifies the messages by the blake2b-256 hash of the data.
+
+use std::{fs::File, path::Path, str::FromStr, time::Duration};
+
+use blake2::{
+    digest::{Update, VariableOutput},
+    Blake2bVar,
+};
+use libp2p::gossipsub;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageIdFn {
+    /// Blake2b-256 of the data, as the Mina daemon does.
+    Blake2b,
+    /// The source peer and the sequence number, the rust-libp2p default.
+    SourceSequence,
+}
+
+impl FromStr for MessageIdFn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake2b" => Ok(MessageIdFn::Blake2b),
+            "source_sequence" => Ok(MessageIdFn::SourceSequence),
+            _ => Err(format!(
+                "unknown message id function {s}, expected blake2b or source_sequence"
+            )),
+        }
+    }
+}
+
+/// Peer scoring is off unless the thresholds are given, as in the Mina daemon.
+/// The peers are scored with the default parameters of rust-libp2p, not with
+/// those of libp2p_helper, so the scores are not the ones a Mina node computes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreThresholds {
+    pub gossip_threshold: f64,
+    pub publish_threshold: f64,
+    pub graylist_threshold: f64,
+    pub accept_px_threshold: f64,
+    pub opportunistic_graft_threshold: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// Subscribe to all of them, publish to the first one.
+    pub topics: Vec<String>,
+    /// `D`, the number of peers in the mesh.
+    pub mesh_n: usize,
+    /// `D_lo`, graft more peers when the mesh is smaller.
+    pub mesh_n_low: usize,
+    /// `D_hi`, prune peers when the mesh is larger.
+    pub mesh_n_high: usize,
+    /// `D_lazy`, the number of peers to gossip to.
+    pub gossip_lazy: usize,
+    pub heartbeat_interval_ms: u64,
+    pub message_id: MessageIdFn,
+    /// Hold the messages until the application accepts them.
+    /// Only `record` validates, the other commands turn it off.
+    pub validate: bool,
+    /// Publish to all peers rather than to the mesh, `--enable-flooding` of the daemon.
+    pub flood_publish: bool,
+    pub max_transmit_size: usize,
+    pub scoring: Option<ScoreThresholds>,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            topics: vec!["coda/consensus-messages/0.0.1".to_owned()],
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            gossip_lazy: 6,
+            heartbeat_interval_ms: 1000,
+            message_id: MessageIdFn::Blake2b,
+            validate: false,
+            flood_publish: false,
+            max_transmit_size: 1024 * 1024 * 32,
+            scoring: None,
+        }
+    }
+}
+
+fn blake2b_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let mut hash = [0; 32];
+    Blake2bVar::new(32)
+        .expect("valid constant")
+        .chain(&message.data)
+        .finalize_variable(&mut hash)
+        .expect("good buffer size");
+    gossipsub::MessageId::new(&hash)
+}
+
+impl GossipConfig {
+    /// Read the JSON file, the missing fields take the default value.
+    pub fn load<P>(path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file =
+            File::open(path).unwrap_or_else(|err| panic!("cannot open {}: {err}", path.display()));
+        serde_json::from_reader(file)
+            .unwrap_or_else(|err| panic!("cannot parse {}: {err}", path.display()))
+    }
+
+    pub fn topic(&self) -> gossipsub::IdentTopic {
+        let topic = self.topics.first().expect("at least one topic");
+        gossipsub::IdentTopic::new(topic)
+    }
+
+    pub fn build(
+        &self,
+        message_authenticity: gossipsub::MessageAuthenticity,
+    ) -> gossipsub::Behaviour {
+        let mut builder = gossipsub::ConfigBuilder::default();
+        builder
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            // go-libp2p-pubsub `Dout`, must fit the mesh
+            .mesh_outbound_min(2.min(self.mesh_n_low).min(self.mesh_n / 2))
+            .gossip_lazy(self.gossip_lazy)
+            .heartbeat_interval(Duration::from_millis(self.heartbeat_interval_ms))
+            .flood_publish(self.flood_publish)
+            .max_transmit_size(self.max_transmit_size);
+        if self.message_id == MessageIdFn::Blake2b {
+            builder.message_id_fn(blake2b_message_id);
+        }
+        if self.validate {
+            // the messages are forwarded once the validator accepts them
+            builder.validate_messages();
+        }
+        let config = builder
+            .build()
+            .unwrap_or_else(|err| panic!("bad gossipsub config: {err}"));
+
+        let mut gossipsub: gossipsub::Behaviour =
+            gossipsub::Behaviour::new(message_authenticity, config).expect(
+                "strict validation mode must be compatible with this `message_authenticity`",
+            );
+        if let Some(scoring) = &self.scoring {
+            log::warn!("peer scoring with the rust-libp2p parameters, not the Mina ones");
+            let thresholds = gossipsub::PeerScoreThresholds {
+                gossip_threshold: scoring.gossip_threshold,
+                publish_threshold: scoring.publish_threshold,
+                graylist_threshold: scoring.graylist_threshold,
+                accept_px_threshold: scoring.accept_px_threshold,
+                opportunistic_graft_threshold: scoring.opportunistic_graft_threshold,
+            };
+            gossipsub
+                .with_peer_score(gossipsub::PeerScoreParams::default(), thresholds)
+                .unwrap_or_else(|err| panic!("bad peer score config: {err}"));
+        }
+        for topic in &self.topics {
+            gossipsub
+                .subscribe(&gossipsub::IdentTopic::new(topic))
+                .unwrap();
+        }
+        gossipsub
+    }
+}
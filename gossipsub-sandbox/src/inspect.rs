@@ -0,0 +1,196 @@
+use std::{io::Write, time::Duration};
+
+use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+use serde::Serialize;
+
+use super::{message, message::Kind, recording::RecordingReader};
+
+pub struct InspectOptions {
+    /// Print every message as a JSON line instead of the table.
+    pub json: bool,
+    /// Only these kinds, all if empty.
+    pub kind: Vec<Kind>,
+    /// Offset from the first message of the recording.
+    pub start: Duration,
+    /// Offset from the first message of the recording.
+    pub end: Option<Duration>,
+}
+
+/// The interesting part of the message.
+#[derive(Serialize, Default)]
+pub struct Summary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_hash: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub work_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prover: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transactions: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    /// Seconds from the first message of the recording.
+    offset: f64,
+    source: Option<String>,
+    id: String,
+    size: usize,
+    kind: &'static str,
+    #[serde(flatten)]
+    summary: Summary,
+    message: &'a GossipNetMessageV2,
+}
+
+/// The string the value has in JSON, hashes and public keys are base58 there.
+fn json_string<T>(value: &T) -> String
+where
+    T: Serialize,
+{
+    match serde_json::to_value(value).unwrap() {
+        serde_json::Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+/// The work is identified by the ledgers its statements go from and to.
+/// There are one or two statements, look for them anywhere in the JSON.
+fn work_ids(value: &serde_json::Value, ids: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(map)
+            if map.contains_key("source") && map.contains_key("target") =>
+        {
+            let ledger = |registers: &str, ledger: &str| {
+                value[registers][ledger].as_str().unwrap_or("?").to_owned()
+            };
+            ids.push(format!(
+                "{}->{}",
+                ledger("source", "first_pass_ledger"),
+                ledger("target", "second_pass_ledger"),
+            ));
+        }
+        serde_json::Value::Object(map) => map.values().for_each(|value| work_ids(value, ids)),
+        serde_json::Value::Array(values) => values.iter().for_each(|value| work_ids(value, ids)),
+        _ => {}
+    }
+}
+
+pub fn summarize(msg: &GossipNetMessageV2) -> Summary {
+    match msg {
+        GossipNetMessageV2::NewState(block) => {
+            let protocol_state = &block.header.protocol_state;
+            Summary {
+                height: Some(
+                    protocol_state
+                        .body
+                        .consensus_state
+                        .blockchain_length
+                        .as_u32(),
+                ),
+                state_hash: Some(protocol_state.hash().to_string()),
+                ..Default::default()
+            }
+        }
+        GossipNetMessageV2::SnarkPoolDiff(diff) => match diff {
+            v2::NetworkPoolSnarkPoolDiffVersionedStableV2::AddSolvedWork(work) => {
+                let (statement, solution) = work.as_ref();
+                let mut ids = vec![];
+                work_ids(&serde_json::to_value(statement).unwrap(), &mut ids);
+                Summary {
+                    work_ids: ids,
+                    fee: Some(json_string(&solution.fee.fee)),
+                    prover: Some(json_string(&solution.fee.prover)),
+                    ..Default::default()
+                }
+            }
+            v2::NetworkPoolSnarkPoolDiffVersionedStableV2::Empty => Summary::default(),
+        },
+        GossipNetMessageV2::TransactionPoolDiff(diff) => Summary {
+            transactions: Some(diff.0.len()),
+            ..Default::default()
+        },
+    }
+}
+
+pub fn run(mut reader: RecordingReader, options: InspectOptions) {
+    let Some(first) = reader.index().unwrap().first().map(|entry| entry.timestamp) else {
+        return;
+    };
+    reader.seek_time(first + options.start).unwrap();
+    let end = options.end.map(|end| first + end);
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if !options.json {
+        writeln!(
+            out,
+            "{:>10} {:<22} {:>9} {:<52} details",
+            "offset", "kind", "size", "source"
+        )
+        .unwrap();
+    }
+    for msg in reader {
+        let msg = msg.unwrap();
+        if end.map_or(false, |end| msg.timestamp > end) {
+            break;
+        }
+        let decoded = match message::decode(&msg.data) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                log::warn!("cannot decode the message {}: {err}", hex::encode(&msg.id));
+                continue;
+            }
+        };
+        let kind = Kind::of(&decoded);
+        if !options.kind.is_empty() && !options.kind.contains(&kind) {
+            continue;
+        }
+        let offset = msg.timestamp.duration_since(first).unwrap_or_default();
+        let source = msg.source.map(|peer_id| peer_id.to_string());
+        let summary = summarize(&decoded);
+
+        if options.json {
+            let line = JsonLine {
+                offset: offset.as_secs_f64(),
+                source,
+                id: hex::encode(&msg.id),
+                size: msg.data.len(),
+                kind: kind.as_str(),
+                summary,
+                message: &decoded,
+            };
+            serde_json::to_writer(&mut out, &line).unwrap();
+            writeln!(out).unwrap();
+        } else {
+            let details = match kind {
+                Kind::NewState => format!(
+                    "height {} {}",
+                    summary.height.unwrap_or_default(),
+                    summary.state_hash.unwrap_or_default(),
+                ),
+                Kind::SnarkPoolDiff => format!(
+                    "fee {} prover {} work {}",
+                    summary.fee.as_deref().unwrap_or("-"),
+                    summary.prover.as_deref().unwrap_or("-"),
+                    summary.work_ids.join(" "),
+                ),
+                Kind::TransactionPoolDiff => {
+                    format!("{} transactions", summary.transactions.unwrap_or_default())
+                }
+            };
+            writeln!(
+                out,
+                "{:>10.3} {:<22} {:>9} {:<52} {details}",
+                offset.as_secs_f64(),
+                kind.as_str(),
+                msg.data.len(),
+                source.as_deref().unwrap_or("-"),
+            )
+            .unwrap();
+        }
+    }
+}
@@ -1,8 +1,35 @@
-use std::{env, path::PathBuf, fs::{File, self}, io::{Write, Read}};
+mod archive;
+mod config;
+mod inspect;
+mod message;
+mod node;
+mod observe;
+mod recording;
+mod replay;
+mod synthesize;
+mod transform;
+mod validate;
 
-use libp2p::{Multiaddr, gossipsub, futures::StreamExt, swarm::SwarmEvent};
-use mina_transport::ed25519::SecretKey;
+use std::{path::PathBuf, fs::{File, self}, time::{Duration, SystemTime}};
+
+use libp2p::{Multiaddr, gossipsub};
+use mina_transport::{
+    keystore, Keypair, Discovery, ConnectionManager, ConnectionManagerConfig, ExternalAddresses,
+};
 use structopt::StructOpt;
+use tokio::time::Instant;
+
+use self::{
+    config::{GossipConfig, MessageIdFn},
+    inspect::InspectOptions,
+    message::Kind,
+    node::{Behaviour, Event, Node},
+    recording::{RecordingReader, RecordingWriter},
+    replay::ReplayOptions,
+    synthesize::SynthesizeOptions,
+    transform::Transform,
+    validate::Validator,
+};
 
 #[derive(StructOpt)]
 struct Args {
@@ -14,21 +41,228 @@ struct Args {
     listen: Vec<Multiaddr>,
     #[structopt(long)]
     peer: Vec<Multiaddr>,
+    /// Only talk to the given peers, do not look for more with Kademlia.
+    /// Without `--peer` dial all the known Berkeley peers rather than the three seeds.
+    #[structopt(long)]
+    no_discovery: bool,
+    /// Stop dialing discovered peers when connected to this many.
+    #[structopt(long, default_value = "50")]
+    max_peers: usize,
+    /// Refuse inbound connections above this many, also counting those in the handshake.
+    #[structopt(long, default_value = "50")]
+    max_inbound: u32,
+    /// Do not dial anybody, wait for the peers to connect. `--peer` only seeds
+    /// the routing table. The external address is logged once enough peers report it.
+    #[structopt(long)]
+    listen_only: bool,
+    /// How many peers must report the same address before it is confirmed.
+    #[structopt(long, default_value = "2")]
+    min_observers: usize,
+    /// The keypair file in Mina format, created if missing, so the peer id is stable.
+    /// The password is taken from `MINA_LIBP2P_PASS`.
+    /// Without it the key is taken from `OPENMINA_P2P_SEC_KEY` or generated.
+    #[structopt(long)]
+    identity: Option<PathBuf>,
+    /// The gossipsub parameters as JSON, the missing ones take the Mina defaults.
+    /// The options below override the file.
+    #[structopt(long)]
+    gossip_config: Option<PathBuf>,
+    /// Subscribe to the topic, may be given several times. Publish to the first one.
+    #[structopt(long)]
+    topic: Vec<String>,
+    #[structopt(long)]
+    mesh_n: Option<usize>,
+    #[structopt(long)]
+    mesh_n_low: Option<usize>,
+    #[structopt(long)]
+    mesh_n_high: Option<usize>,
+    #[structopt(long)]
+    heartbeat_ms: Option<u64>,
+    /// `blake2b` as the Mina daemon or `source_sequence` as rust-libp2p.
+    #[structopt(long)]
+    message_id: Option<MessageIdFn>,
     #[structopt(subcommand)]
     cmd: Command,
 }
 
 #[derive(StructOpt)]
 enum Command {
-    Record,
-    Replay,
+    /// Generate a keypair file in Mina format, encrypted with `MINA_LIBP2P_PASS` if set.
+    Keygen {
+        path: PathBuf,
+    },
+    /// Record the messages of the consensus topic.
+    Record {
+        /// Record only these kinds: `new_state`, `snark_pool_diff`, `transaction_pool_diff`.
+        /// All kinds if not given.
+        #[structopt(long)]
+        kind: Vec<Kind>,
+        /// Check every message before it is forwarded, reject the malformed ones.
+        /// The rejections per peer are written to `validation.json` every minute.
+        #[structopt(long)]
+        validate: bool,
+        /// Reject the snark work with a higher fee, in nanomina.
+        #[structopt(long, default_value = "100000000000")]
+        max_snark_fee: u64,
+        /// Reject the blocks of other chains, the hash is in the genesis
+        /// config of the network, the same that makes the chain id.
+        #[structopt(long)]
+        genesis_state_hash: Option<String>,
+    },
+    /// Publish the recorded messages with the recorded intervals between them.
+    Replay {
+        /// Wait until the mesh of the topic has this many peers.
+        #[structopt(long, default_value = "1")]
+        min_mesh: usize,
+        /// Faster or slower than recorded, `10` publishes ten times faster.
+        #[structopt(long, default_value = "1", parse(try_from_str = parse_speed))]
+        speed: f64,
+        /// Start again when the end is reached. Refused if a pass takes
+        /// less than two minutes, the peers would drop the repeated messages.
+        #[structopt(long = "loop")]
+        repeat: bool,
+        /// Skip the first seconds of the recording.
+        #[structopt(long, default_value = "0")]
+        start: f64,
+        /// Stop at this many seconds from the beginning of the recording.
+        #[structopt(long)]
+        end: Option<f64>,
+        /// Replay only these kinds, all if not given.
+        #[structopt(long)]
+        kind: Vec<Kind>,
+        /// Rewrite the fee of the snark work, in nanomina.
+        #[structopt(long)]
+        snark_fee: Option<u64>,
+        /// Rewrite the prover of the snark work, a base58 public key.
+        /// The proofs no longer match, the local pool must not verify them.
+        #[structopt(long)]
+        prover: Option<String>,
+        /// JSON file with the list of the ledger hashes of the local chain,
+        /// the snark work that starts from another ledger is dropped.
+        #[structopt(long)]
+        ledgers: Option<PathBuf>,
+    },
+    /// Publish the blocks recorded by bootstrap-sandbox as `NewState`, in chain order.
+    Synthesize {
+        /// The directory of the bootstrap-sandbox recording, the one that has `blocks`.
+        #[structopt(long, default_value = "target/default")]
+        recording: PathBuf,
+        /// Wait until the mesh of the topic has this many peers.
+        #[structopt(long, default_value = "1")]
+        min_mesh: usize,
+        /// Seconds between the blocks. By default the blocks are paced by their slots.
+        #[structopt(long)]
+        interval: Option<f64>,
+        /// Faster or slower than the slots, `10` publishes ten times faster.
+        #[structopt(long, default_value = "1", parse(try_from_str = parse_speed))]
+        speed: f64,
+    },
+    /// Run several observers with their own identities and peers,
+    /// measure how long each message takes to reach all of them.
+    /// Writes every arrival to `arrivals.jsonl` and the statistics to `propagation.json`.
+    Observe {
+        #[structopt(long, default_value = "3")]
+        observers: usize,
+        /// Seconds to observe.
+        #[structopt(long, default_value = "600")]
+        duration: u64,
+    },
+    /// Store every block heard over gossip and fetch its missing ancestors with RPC,
+    /// into the `blocks` layout of bootstrap-sandbox.
+    Archive {
+        /// The directory of the bootstrap-sandbox recording, the one that has `blocks`.
+        #[structopt(long, default_value = "target/default")]
+        recording: PathBuf,
+        /// How many ancestors below the best block to fetch.
+        #[structopt(long, default_value = "290")]
+        depth: u32,
+        /// Do not archive the blocks of other chains.
+        #[structopt(long)]
+        genesis_state_hash: Option<String>,
+    },
+    /// Decode the recorded messages and print them as a table or as JSON lines.
+    Inspect {
+        #[structopt(long)]
+        json: bool,
+        /// Only these kinds, all if not given.
+        #[structopt(long)]
+        kind: Vec<Kind>,
+        /// Skip the first seconds of the recording.
+        #[structopt(long, default_value = "0")]
+        start: f64,
+        /// Stop at this many seconds from the beginning of the recording.
+        #[structopt(long)]
+        end: Option<f64>,
+    },
+    /// Convert the old recording, the concatenated messages, into the current format.
+    Import {
+        raw: PathBuf,
+        /// The messages of the old recording have no timestamp,
+        /// they are spaced by this many milliseconds.
+        #[structopt(long, default_value = "1000")]
+        interval_ms: u64,
+    },
 }
 
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        Ok(_) => Err(format!("the speed must be positive, not {s}")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The recording is written out this often, a recording killed
+/// other than with ctrl+c loses the messages of the last interval.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let Args { path, chain_id, listen, mut peer, cmd } = Args::from_args();
+    let Args {
+        path,
+        chain_id,
+        listen,
+        mut peer,
+        no_discovery,
+        max_peers,
+        max_inbound,
+        listen_only,
+        min_observers,
+        identity,
+        gossip_config,
+        topic,
+        mesh_n,
+        mesh_n_low,
+        mesh_n_high,
+        heartbeat_ms,
+        message_id,
+        cmd,
+    } = Args::from_args();
+
+    let mut gossip_config = gossip_config.map(GossipConfig::load).unwrap_or_default();
+    if !topic.is_empty() {
+        gossip_config.topics = topic;
+    }
+    gossip_config.mesh_n = mesh_n.unwrap_or(gossip_config.mesh_n);
+    gossip_config.mesh_n_low = mesh_n_low.unwrap_or(gossip_config.mesh_n_low);
+    gossip_config.mesh_n_high = mesh_n_high.unwrap_or(gossip_config.mesh_n_high);
+    gossip_config.heartbeat_interval_ms = heartbeat_ms.unwrap_or(gossip_config.heartbeat_interval_ms);
+    gossip_config.message_id = message_id.unwrap_or(gossip_config.message_id);
+    match &cmd {
+        Command::Record { validate: true, .. } => gossip_config.validate = true,
+        Command::Record { .. } => {}
+        // nothing else validates, the held messages would never be forwarded
+        _ if gossip_config.validate => {
+            log::warn!("only `record` validates the messages, ignore `validate` of the config");
+            gossip_config.validate = false;
+        }
+        _ => {}
+    }
+    let topic = gossip_config.topic();
 
     let default_peer = [
         "/dns4/seed-1.berkeley.o1test.net/tcp/10000/p2p/12D3KooWAdgYL6hv18M3iDBdaK1dRygPivSfAfBNDzie6YqydVbs",
@@ -57,62 +291,223 @@ async fn main() {
         "/ip4/34.29.161.11/tcp/10946/p2p/12D3KooWCntSrMqSiovXcVfMZ56aYbzpZoh4mi7gJJNiZBmzXrpa",
         "/ip4/35.238.71.15/tcp/23676/p2p/12D3KooWENsfMszNYBRfHZJUEAvXKThmZU3nijWVbLivq33AE2Vk",
     ].map(|s| s.parse().unwrap());
-    if peer.is_empty() {
-        peer.extend(default_peer);
+    if peer.is_empty() && !listen_only {
+        // the seeds are enough, the rest of the network is found by the discovery,
+        // without it dial all the peers we know
+        let seeds = if no_discovery { default_peer.len() } else { 3 };
+        peer.extend(default_peer.into_iter().take(seeds));
     }
 
-    let sk = env::var("OPENMINA_P2P_SEC_KEY")
-        .map(|key| {
-            let mut bytes = bs58::decode(key).with_check(Some(0x80)).into_vec().unwrap();
-            SecretKey::from_bytes(&mut bytes[1..]).unwrap()
-        })
-        .unwrap_or_else(|_| {
-            let mut bytes = rand::random::<[u8; 32]>();
-            log::info!("{}", bs58::encode(&bytes).with_check_version(0x80).into_string());
-            let sk = SecretKey::from_bytes(&mut bytes).unwrap();
-            sk
+    if let Command::Inspect { json, kind, start, end } = cmd {
+        let reader = RecordingReader::open(path.join("gossip")).unwrap();
+        let options = InspectOptions {
+            json,
+            kind,
+            start: Duration::from_secs_f64(start),
+            end: end.map(Duration::from_secs_f64),
+        };
+        inspect::run(reader, options);
+        return Ok(());
+    }
+
+    if let Command::Import { raw, interval_ms } = &cmd {
+        fs::create_dir_all(&path).unwrap();
+        let mut writer = RecordingWriter::create(path.join("gossip")).unwrap();
+        let raw = File::open(raw).unwrap();
+        let interval = Duration::from_millis(*interval_ms);
+        let count = recording::import_raw(raw, &gossip_config.topics[0], SystemTime::now(), interval, &mut writer)
+            .unwrap_or_else(|err| panic!("cannot import: {err}"));
+        log::info!("imported {count} messages");
+        return Ok(());
+    }
+
+    if let Command::Keygen { path } = &cmd {
+        let local_key = keystore::generate(path)?;
+        println!("{}", local_key.public().to_peer_id());
+        return Ok(());
+    }
+
+    // the discovered peers are still added to the routing table, but never dialed
+    let max_peers = if listen_only { 0 } else { max_peers };
+    let build_node = |local_key: Keypair,
+                      listen: Vec<Multiaddr>,
+                      peer: Vec<Multiaddr>,
+                      rpc: Option<libp2p_rpc_behaviour::Behaviour>| {
+        let external = ExternalAddresses::new(local_key.public().to_peer_id(), min_observers);
+
+        let message_authenticity = gossipsub::MessageAuthenticity::Signed(local_key.clone());
+        let gossipsub = gossip_config.build(message_authenticity);
+        let mut discovery = if no_discovery {
+            Discovery::identify_only(&local_key)
+        } else {
+            Discovery::new(&local_key)
+        };
+        discovery.add_seeds(&peer);
+        // listening only, the given peers are just the seeds of the routing table
+        let peer = if listen_only { vec![] } else { peer };
+        // keep the given peers connected, they are the way into the network
+        let manager = ConnectionManager::new(ConnectionManagerConfig {
+            max_inbound: Some(max_inbound),
+            max_pending_inbound: Some(max_inbound),
+            peers: peer.clone(),
+            ..Default::default()
         });
+        let behaviour = Behaviour {
+            gossipsub,
+            discovery,
+            manager,
+            rpc: rpc.into(),
+        };
 
-    let local_key: libp2p::identity::Keypair = mina_transport::ed25519::Keypair::from(sk).into();
-    log::info!("{}", local_key.public().to_peer_id());
+        let swarm = mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour)?;
+        Ok::<_, mina_transport::TransportError>(Node::new(swarm, external, max_peers))
+    };
 
-    let message_authenticity = gossipsub::MessageAuthenticity::Signed(local_key.clone());
-    let gossipsub_config = gossipsub::ConfigBuilder::default()
-        .max_transmit_size(1024 * 1024 * 32)
-        .build()
-        .expect("the config must be a valid constant");
-    let mut behaviour = gossipsub::Behaviour::<gossipsub::IdentityTransform, gossipsub::subscription_filter::AllowAllSubscriptionFilter>::new(message_authenticity, gossipsub_config)
-        .expect("strict validation mode must be compatible with this `message_authenticity`");
-    let topic = gossipsub::IdentTopic::new("coda/consensus-messages/0.0.1");
-    behaviour.subscribe(&topic).unwrap();
+    if let Command::Observe { observers, duration } = cmd {
+        if observers == 0 {
+            return Err("at least one observer is needed".into());
+        }
+        // sharing a peer, the observers would measure that peer rather than the network
+        if peer.len() < observers {
+            let msg = format!(
+                "{} peers, not enough for {observers} observers to have their own",
+                peer.len(),
+            );
+            return Err(msg.into());
+        }
+        // each observer starts from its own share of the peers
+        let mut nodes = vec![];
+        for i in 0..observers {
+            let peer = peer.iter().skip(i).step_by(observers).cloned().collect();
+            let listen = if i == 0 { listen.clone() } else { vec![] };
+            let local_key = mina_transport::generate_identity();
+            log::info!("observer {i} is {}", local_key.public().to_peer_id());
+            nodes.push(build_node(local_key, listen, peer, None)?);
+        }
+        fs::create_dir_all(&path)?;
+        observe::run(nodes, &path, Duration::from_secs(duration)).await;
+        return Ok(());
+    }
 
-    let mut swarm = mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour);
+    let local_key = keystore::load_or_generate(identity.as_deref())?;
+    let rpc = matches!(cmd, Command::Archive { .. }).then(archive::behaviour);
+    let mut node = build_node(local_key, listen, peer, rpc)?;
 
     match cmd {
-        Command::Record => {
-            fs::create_dir_all(&path).unwrap();
-            let mut file = File::create(path.join("snark_pool_diff")).unwrap();
-            while let Some(event) = swarm.next().await {
-                match event {
-                    SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) => {
-                        // GossipNetMessageV2::SnarkPoolDiff
-                        if message.data[8] == 1 {
-                            file.write_all(&message.data).unwrap();
+        Command::Keygen { .. }
+        | Command::Import { .. }
+        | Command::Inspect { .. }
+        | Command::Observe { .. } => {}
+        Command::Archive { recording, depth, genesis_state_hash } => {
+            archive::run(&mut node, recording, depth, genesis_state_hash).await;
+        }
+        Command::Record { kind, max_snark_fee, genesis_state_hash, .. } => {
+            fs::create_dir_all(&path)?;
+            let mut writer = RecordingWriter::create(path.join("gossip"))?;
+            let mut validator = gossip_config
+                .validate
+                .then(|| Validator::new(max_snark_fee, genesis_state_hash));
+            let report = |validator: &Option<Validator>| {
+                if let Some(validator) = validator {
+                    if let Err(err) = validator.report(path.join("validation.json")) {
+                        log::error!("cannot write the validation report: {err}");
+                    }
+                }
+            };
+            // the recording is usually stopped with ctrl+c, keep it mostly complete
+            let ctrl_c = tokio::signal::ctrl_c();
+            tokio::pin!(ctrl_c);
+            let mut next_flush = Instant::now() + FLUSH_INTERVAL;
+            let mut next_report = Instant::now() + REPORT_INTERVAL;
+            loop {
+                let event = tokio::select! {
+                    event = node.next(Some(next_flush.min(next_report))) => event,
+                    _ = &mut ctrl_c => break,
+                };
+                let event = match event {
+                    Some(event) => event,
+                    None => {
+                        let now = Instant::now();
+                        writer.flush()?;
+                        next_flush = now + FLUSH_INTERVAL;
+                        if now >= next_report {
+                            report(&validator);
+                            next_report = now + REPORT_INTERVAL;
+                        }
+                        continue;
+                    }
+                };
+                let Event::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                }) = event else {
+                    continue;
+                };
+                let msg = match &mut validator {
+                    Some(validator) => {
+                        let (acceptance, msg) = validator.validate(propagation_source, &message.data);
+                        let rejected = matches!(acceptance, gossipsub::MessageAcceptance::Reject);
+                        let res = node.gossipsub()
+                            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+                        if let Err(err) = res {
+                            log::warn!("cannot forward the message: {err:?}");
+                        }
+                        // the malformed messages would break the replay
+                        match msg {
+                            Some(msg) if !rejected => msg,
+                            _ => continue,
                         }
                     }
-                    _ => {}
+                    None => match message::decode(&message.data) {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("cannot decode the message from {propagation_source}: {err}");
+                            continue;
+                        }
+                    },
+                };
+                let msg_kind = Kind::of(&msg);
+                log::debug!("{msg_kind} from {propagation_source}, {} bytes", message.data.len());
+                if kind.is_empty() || kind.contains(&msg_kind) {
+                    writer
+                        .write(&recording::Message {
+                            timestamp: SystemTime::now(),
+                            source: Some(propagation_source),
+                            id: message_id.0,
+                            sequence_number: message.sequence_number,
+                            topic: message.topic.into_string(),
+                            data: message.data,
+                        })?;
                 }
             }
+            writer.flush()?;
+            report(&validator);
         }
-        Command::Replay => {
-            let mut file = File::open(path.join("snark_pool_diff")).unwrap();
-            let mut len = [0; 8];
-            while let Ok(()) = file.read_exact(&mut len) {
-                let mut data = vec![0; (8 + u64::from_le_bytes(len)) as usize];
-                data[..8].clone_from_slice(&len);
-                file.read_exact(&mut data[8..]).unwrap();
-                swarm.behaviour_mut().publish(topic.clone(), data).unwrap();
-            }
+        Command::Replay { min_mesh, speed, repeat, start, end, kind, snark_fee, prover, ledgers } => {
+            let reader = RecordingReader::open(path.join("gossip")).unwrap();
+            let options = ReplayOptions {
+                min_mesh,
+                speed,
+                repeat,
+                start: Duration::from_secs_f64(start),
+                end: end.map(Duration::from_secs_f64),
+                kind,
+                transform: Transform::new(snark_fee, prover, ledgers)?,
+            };
+            replay::run(&mut node, reader, &topic, options).await;
+        }
+        Command::Synthesize { recording, min_mesh, interval, speed } => {
+            let chain = synthesize::load_chain(recording);
+            log::info!("loaded {} blocks", chain.len());
+            let options = SynthesizeOptions {
+                min_mesh,
+                interval: interval.map(Duration::from_secs_f64),
+                speed,
+            };
+            synthesize::run(&mut node, chain, &topic, options).await;
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,148 @@
+use std::{fmt, io, str::FromStr};
+
+use binprot::{BinProtRead, BinProtWrite};
+use mina_p2p_messages::gossip::GossipNetMessageV2;
+
+/// The variant of `GossipNetMessageV2`, the byte after the length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    NewState,
+    SnarkPoolDiff,
+    TransactionPoolDiff,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 3] = [
+        Kind::NewState,
+        Kind::SnarkPoolDiff,
+        Kind::TransactionPoolDiff,
+    ];
+
+    pub fn of(msg: &GossipNetMessageV2) -> Self {
+        match msg {
+            GossipNetMessageV2::NewState(_) => Kind::NewState,
+            GossipNetMessageV2::SnarkPoolDiff(_) => Kind::SnarkPoolDiff,
+            GossipNetMessageV2::TransactionPoolDiff(_) => Kind::TransactionPoolDiff,
+        }
+    }
+
+    /// Look at the tag without decoding the whole message.
+    pub fn of_frame(data: &[u8]) -> Option<Self> {
+        match data.get(8)? {
+            0 => Some(Kind::NewState),
+            1 => Some(Kind::SnarkPoolDiff),
+            2 => Some(Kind::TransactionPoolDiff),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::NewState => "new_state",
+            Kind::SnarkPoolDiff => "snark_pool_diff",
+            Kind::TransactionPoolDiff => "transaction_pool_diff",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| {
+                let all = Kind::ALL.map(|kind| kind.as_str()).join(", ");
+                format!("unknown message kind {s}, expected one of: {all}")
+            })
+    }
+}
+
+/// Decode the gossipsub message data: 8 bytes little endian length, then the binprot.
+/// The length must match the payload and the binprot must take all of it.
+pub fn decode(data: &[u8]) -> Result<GossipNetMessageV2, binprot::Error> {
+    if data.len() < 8 {
+        return Err(invalid(format!("{} bytes, no length prefix", data.len())));
+    }
+    let (prefix, mut payload) = data.split_at(8);
+    let len = u64::from_le_bytes(prefix.try_into().expect("cannot fail"));
+    let payload_len = payload.len();
+    if len != payload_len as u64 {
+        let msg = format!("the length prefix is {len}, the payload is {payload_len}");
+        return Err(invalid(msg));
+    }
+    let msg = GossipNetMessageV2::binprot_read(&mut payload)?;
+    if !payload.is_empty() {
+        let trailing = payload.len();
+        return Err(invalid(format!("{trailing} bytes after the message")));
+    }
+    Ok(msg)
+}
+
+fn invalid(msg: String) -> binprot::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Encode the message as gossipsub message data, the length prefix included.
+pub fn encode(msg: &GossipNetMessageV2) -> Vec<u8> {
+    let mut data = vec![0; 8];
+    msg.binprot_write(&mut data)
+        .expect("writing into a vector cannot fail");
+    let len = (data.len() - 8) as u64;
+    data[..8].clone_from_slice(&len.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+
+    use super::{decode, encode, Kind};
+
+    fn empty_snark_pool_diff() -> Vec<u8> {
+        let msg =
+            GossipNetMessageV2::SnarkPoolDiff(v2::NetworkPoolSnarkPoolDiffVersionedStableV2::Empty);
+        encode(&msg)
+    }
+
+    #[test]
+    fn decode_encoded() {
+        let data = empty_snark_pool_diff();
+        assert_eq!(Kind::of_frame(&data), Some(Kind::SnarkPoolDiff));
+        let msg = decode(&data).unwrap();
+        assert_eq!(Kind::of(&msg), Kind::SnarkPoolDiff);
+    }
+
+    #[test]
+    fn decode_no_length_prefix() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0; 7]).is_err());
+    }
+
+    #[test]
+    fn decode_length_prefix_longer_than_payload() {
+        let mut data = empty_snark_pool_diff();
+        let len = (data.len() - 8 + 1) as u64;
+        data[..8].clone_from_slice(&len.to_le_bytes());
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn decode_trailing_bytes() {
+        let mut data = empty_snark_pool_diff();
+        // without the prefix counting them
+        data.push(0);
+        assert!(decode(&data).is_err());
+        // and with the prefix counting them
+        let len = (data.len() - 8) as u64;
+        data[..8].clone_from_slice(&len.to_le_bytes());
+        assert!(decode(&data).is_err());
+    }
+}
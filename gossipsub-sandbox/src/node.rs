@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use libp2p::{
+    futures::StreamExt,
+    gossipsub,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, Swarm, SwarmEvent},
+    PeerId,
+};
+use mina_transport::{ConnectionManager, Discovery, DiscoveryEvent, ExternalAddresses};
+use tokio::time::{Instant, Interval};
+
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub gossipsub: gossipsub::Behaviour,
+    /// Kademlia is off with `--no-discovery`, identify is always on.
+    pub discovery: Discovery,
+    pub manager: ConnectionManager,
+    /// Only when the application talks RPC to the peers.
+    pub rpc: Toggle<libp2p_rpc_behaviour::Behaviour>,
+}
+
+/// What the application gets from the swarm.
+pub enum Event {
+    Gossipsub(gossipsub::Event),
+    Rpc(PeerId, libp2p_rpc_behaviour::Event),
+}
+
+/// The swarm and everything that must happen while it runs:
+/// discovery, its periodic bootstrap and the external address tracking.
+pub struct Node {
+    pub swarm: Swarm<Behaviour>,
+    external: ExternalAddresses,
+    max_peers: usize,
+    bootstrap: Interval,
+}
+
+impl Node {
+    /// The discovered peers are dialed until there are `max_peers` connections.
+    /// With zero nothing is dialed and the routing table is not refreshed either,
+    /// the node only answers the Kademlia queries of others.
+    pub fn new(swarm: Swarm<Behaviour>, external: ExternalAddresses, max_peers: usize) -> Self {
+        Node {
+            swarm,
+            external,
+            max_peers,
+            bootstrap: tokio::time::interval(Duration::from_secs(60)),
+        }
+    }
+
+    pub fn gossipsub(&mut self) -> &mut gossipsub::Behaviour {
+        &mut self.swarm.behaviour_mut().gossipsub
+    }
+
+    /// Run the swarm until the mesh of the topic has at least `min_mesh` peers.
+    pub async fn wait_for_mesh(&mut self, topic: &gossipsub::TopicHash, min_mesh: usize) {
+        log::info!("waiting for {min_mesh} peers in the mesh");
+        while self.gossipsub().mesh_peers(topic).count() < min_mesh {
+            // the mesh changes on the heartbeat, check at least every second
+            self.next(Some(Instant::now() + Duration::from_secs(1)))
+                .await;
+        }
+    }
+
+    /// Run the swarm until the deadline, ignoring the events.
+    pub async fn run_until(&mut self, deadline: Instant) {
+        while self.next(Some(deadline)).await.is_some() {}
+    }
+
+    /// Run the swarm until the next gossipsub or RPC event.
+    /// Returns `None` if the `deadline` comes first.
+    pub async fn next(&mut self, deadline: Option<Instant>) -> Option<Event> {
+        let deadline = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+
+        loop {
+            let event = tokio::select! {
+                event = self.swarm.select_next_some() => event,
+                _ = self.bootstrap.tick() => {
+                    if self.max_peers > 0 {
+                        self.swarm.behaviour_mut().discovery.bootstrap();
+                    }
+                    continue;
+                }
+                _ = &mut deadline => return None,
+            };
+            self.external.on_swarm_event(&event);
+            match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    let address =
+                        mina_transport::with_peer_id(&address, *self.swarm.local_peer_id());
+                    log::info!("listen on {address}");
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(event)) => {
+                    return Some(Event::Gossipsub(event))
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Rpc((peer_id, event))) => {
+                    return Some(Event::Rpc(peer_id, event))
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Discovery(event)) => {
+                    if let DiscoveryEvent::Identify(event) = &event {
+                        self.external.on_identify(event);
+                    }
+                    self.on_discovery(&event);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Update the routing table and dial the newly discovered peer if there is room for it.
+    fn on_discovery(&mut self, event: &DiscoveryEvent) {
+        let Some(peer_id) = self.swarm.behaviour_mut().discovery.on_event(event) else {
+            return;
+        };
+        if self.swarm.is_connected(&peer_id)
+            || self.swarm.network_info().num_peers() >= self.max_peers
+        {
+            return;
+        }
+        if let Err(err) = self.swarm.dial(peer_id) {
+            log::warn!("failed to dial discovered peer {peer_id}: {err}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use libp2p::{gossipsub, PeerId};
+use serde::Serialize;
+use tokio::{sync::mpsc, time::Instant};
+
+use super::{
+    message::Kind,
+    node::{Event, Node},
+};
+
+struct Arrival {
+    observer: usize,
+    message_id: gossipsub::MessageId,
+    source: PeerId,
+    time: SystemTime,
+    kind: Option<Kind>,
+    size: usize,
+}
+
+#[derive(Serialize)]
+struct ArrivalLine<'a> {
+    observer: usize,
+    id: String,
+    source: String,
+    /// Milliseconds since unix epoch.
+    time: u64,
+    kind: &'a str,
+    size: usize,
+}
+
+struct MessageArrivals {
+    kind: Option<Kind>,
+    /// The first arrival at each observer, the time and the peer.
+    /// The observers run in their own tasks, the arrivals come in any order.
+    observers: BTreeMap<usize, (SystemTime, PeerId)>,
+}
+
+impl MessageArrivals {
+    /// When the first observer received the message.
+    fn first(&self) -> SystemTime {
+        self.observers
+            .values()
+            .map(|(time, _)| *time)
+            .min()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// The delay of each observer since the first one.
+    fn delays(&self) -> impl Iterator<Item = (usize, Duration, PeerId)> + '_ {
+        let first = self.first();
+        self.observers
+            .iter()
+            .map(move |(observer, (time, source))| {
+                let delay = time.duration_since(first).unwrap_or_default();
+                (*observer, delay, *source)
+            })
+    }
+}
+
+#[derive(Serialize, Default)]
+struct KindStats {
+    messages: usize,
+    /// Messages that reached every observer.
+    complete: usize,
+    /// Milliseconds between the first and the last observer, only the complete messages.
+    spread_p50: u64,
+    spread_p90: u64,
+    spread_max: u64,
+    /// Milliseconds between the first observer and each other one, all messages.
+    delay_mean: f64,
+}
+
+#[derive(Serialize)]
+struct ObserverReport {
+    observer: usize,
+    /// Milliseconds since the first observer received the message.
+    delay: u64,
+    /// The peer the observer received the message from.
+    source: String,
+}
+
+#[derive(Serialize)]
+struct MessageReport<'a> {
+    id: String,
+    kind: &'a str,
+    /// Milliseconds since unix epoch, when the first observer received the message.
+    first: u64,
+    /// Milliseconds between the first and the last observer.
+    spread: u64,
+    observers: Vec<ObserverReport>,
+}
+
+#[derive(Serialize, Default)]
+struct Report<'a> {
+    kinds: BTreeMap<&'a str, KindStats>,
+    messages: Vec<MessageReport<'a>>,
+}
+
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+/// Run the nodes, each in its own task, and collect what they receive.
+pub async fn run(nodes: Vec<Node>, path: &Path, duration: Duration) {
+    let observers = nodes.len();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for (observer, mut node) in nodes.into_iter().enumerate() {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(Event::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
+                    message,
+                })) = node.next(None).await
+                else {
+                    continue;
+                };
+                let arrival = Arrival {
+                    observer,
+                    message_id,
+                    source: propagation_source,
+                    time: SystemTime::now(),
+                    kind: Kind::of_frame(&message.data),
+                    size: message.data.len(),
+                };
+                if tx.send(arrival).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut file = BufWriter::new(File::create(path.join("arrivals.jsonl")).unwrap());
+    let mut messages = BTreeMap::<gossipsub::MessageId, MessageArrivals>::new();
+    let deadline = Instant::now() + duration;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let arrival = tokio::select! {
+            arrival = rx.recv() => match arrival {
+                Some(arrival) => arrival,
+                None => break,
+            },
+            _ = tokio::time::sleep_until(deadline) => break,
+            _ = &mut ctrl_c => break,
+        };
+        let entry = messages
+            .entry(arrival.message_id.clone())
+            .or_insert_with(|| MessageArrivals {
+                kind: arrival.kind,
+                observers: BTreeMap::new(),
+            });
+        // the duplicates are dropped by gossipsub, but be sure
+        entry
+            .observers
+            .entry(arrival.observer)
+            .or_insert((arrival.time, arrival.source));
+
+        let line = ArrivalLine {
+            observer: arrival.observer,
+            id: hex::encode(&arrival.message_id.0),
+            source: arrival.source.to_string(),
+            time: arrival
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            kind: arrival.kind.map_or("unknown", |kind| kind.as_str()),
+            size: arrival.size,
+        };
+        serde_json::to_writer(&mut file, &line).unwrap();
+        writeln!(file).unwrap();
+        file.flush().unwrap();
+    }
+
+    let mut report = Report::default();
+    let mut spreads = BTreeMap::<&str, Vec<u64>>::new();
+    let mut delays = BTreeMap::<&str, Vec<u64>>::new();
+    for (message_id, arrivals) in &messages {
+        let kind = arrivals.kind.map_or("unknown", |kind| kind.as_str());
+        let kind_stats = report.kinds.entry(kind).or_default();
+        kind_stats.messages += 1;
+
+        let mut message_delays = arrivals
+            .delays()
+            .map(|(_, delay, _)| delay.as_millis() as u64)
+            .collect::<Vec<_>>();
+        message_delays.sort_unstable();
+        let spread = message_delays.last().copied().unwrap_or_default();
+        // the first observer is the reference, its delay is zero
+        delays
+            .entry(kind)
+            .or_default()
+            .extend(message_delays.iter().skip(1));
+        if arrivals.observers.len() == observers {
+            kind_stats.complete += 1;
+            spreads.entry(kind).or_default().push(spread);
+        }
+
+        report.messages.push(MessageReport {
+            id: hex::encode(&message_id.0),
+            kind,
+            first: arrivals
+                .first()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            spread,
+            observers: arrivals
+                .delays()
+                .map(|(observer, delay, source)| ObserverReport {
+                    observer,
+                    delay: delay.as_millis() as u64,
+                    source: source.to_string(),
+                })
+                .collect(),
+        });
+    }
+    for (kind, mut spread) in spreads {
+        spread.sort_unstable();
+        let kind_stats = report.kinds.get_mut(kind).expect("inserted above");
+        kind_stats.spread_p50 = percentile(&spread, 50);
+        kind_stats.spread_p90 = percentile(&spread, 90);
+        kind_stats.spread_max = spread.last().copied().unwrap_or_default();
+    }
+    for (kind, delays) in delays {
+        if !delays.is_empty() {
+            let kind_stats = report.kinds.get_mut(kind).expect("inserted above");
+            kind_stats.delay_mean = delays.iter().sum::<u64>() as f64 / delays.len() as f64;
+        }
+    }
+
+    for (kind, s) in &report.kinds {
+        log::info!(
+            "{kind}: {} messages, {} reached all {observers} observers, \
+             spread p50 {} ms p90 {} ms max {} ms, mean delay {:.0} ms",
+            s.messages,
+            s.complete,
+            s.spread_p50,
+            s.spread_p90,
+            s.spread_max,
+            s.delay_mean,
+        );
+    }
+    let file = File::create(path.join("propagation.json")).unwrap();
+    serde_json::to_writer_pretty(file, &report).unwrap();
+}
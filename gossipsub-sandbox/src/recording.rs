@@ -0,0 +1,428 @@
+//! The gossip recording. All integers are little endian.
+//! The file starts with the 8 bytes magic `MINAGOSS` and the 4 bytes version,
+//! then the records follow:
+//!
+//! | size | field                                                  |
+//! |------|--------------------------------------------------------|
+//! | 8    | timestamp, nanoseconds since unix epoch                |
+//! | 1    | length of the propagation source, `0` if unknown       |
+//! | n    | propagation source, the peer id as binary multihash    |
+//! | 2    | length of the gossipsub message id                     |
+//! | n    | message id                                             |
+//! | 1    | `1` if the sequence number follows, `0` otherwise      |
+//! | 8    | sequence number, only if present                       |
+//! | 2    | length of the topic                                    |
+//! | n    | topic                                                  |
+//! | 4    | length of the payload                                  |
+//! | n    | payload, the gossipsub message data as it came          |
+//!
+//! Next to the recording, with the extension `idx`, is the index:
+//! the timestamp and the offset of each record, 8 bytes each.
+//! It is rebuilt from the recording if missing.
+//! A record cut short at the end, e.g. the recording was killed, is ignored.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use libp2p::PeerId;
+
+const MAGIC: &[u8; 8] = b"MINAGOSS";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 12;
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub timestamp: SystemTime,
+    pub source: Option<PeerId>,
+    pub id: Vec<u8>,
+    pub sequence_number: Option<u64>,
+    pub topic: String,
+    pub data: Vec<u8>,
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    path.with_extension("idx")
+}
+
+fn nanos(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+pub struct RecordingWriter {
+    file: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+impl RecordingWriter {
+    pub fn create<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        let index = BufWriter::new(File::create(index_path(path))?);
+        Ok(RecordingWriter {
+            file,
+            index,
+            offset: HEADER_LEN,
+        })
+    }
+
+    pub fn write(&mut self, msg: &Message) -> io::Result<()> {
+        let source = msg
+            .source
+            .map(|peer_id| peer_id.to_bytes())
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(40 + msg.id.len() + msg.topic.len() + msg.data.len());
+        record.extend_from_slice(&nanos(msg.timestamp).to_le_bytes());
+        record.push(source.len() as u8);
+        record.extend_from_slice(&source);
+        record.extend_from_slice(&(msg.id.len() as u16).to_le_bytes());
+        record.extend_from_slice(&msg.id);
+        match msg.sequence_number {
+            Some(sequence_number) => {
+                record.push(1);
+                record.extend_from_slice(&sequence_number.to_le_bytes());
+            }
+            None => record.push(0),
+        }
+        record.extend_from_slice(&(msg.topic.len() as u16).to_le_bytes());
+        record.extend_from_slice(msg.topic.as_bytes());
+        record.extend_from_slice(&(msg.data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&msg.data);
+
+        self.file.write_all(&record)?;
+        self.index.write_all(&nanos(msg.timestamp).to_le_bytes())?;
+        self.index.write_all(&self.offset.to_le_bytes())?;
+        self.offset += record.len() as u64;
+        Ok(())
+    }
+
+    /// Write out the buffered records, the recording first, then the index.
+    /// The records are buffered until then, flush periodically.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.index.flush()
+    }
+}
+
+/// One entry of the index.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexEntry {
+    pub timestamp: SystemTime,
+    pub offset: u64,
+}
+
+pub struct RecordingReader {
+    path: PathBuf,
+    file: BufReader<File>,
+    index: Option<Vec<IndexEntry>>,
+}
+
+impl RecordingReader {
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let mut file = BufReader::new(File::open(&path)?);
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)
+            .map_err(|_| invalid_data("not a gossip recording"))?;
+        if &header[..8] != MAGIC {
+            return Err(invalid_data("not a gossip recording"));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().expect("cannot fail"));
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported recording version {version}"
+            )));
+        }
+        Ok(RecordingReader {
+            path,
+            file,
+            index: None,
+        })
+    }
+
+    /// The index, loaded on first use or rebuilt if the file is missing.
+    pub fn index(&mut self) -> io::Result<&[IndexEntry]> {
+        if self.index.is_none() {
+            let index = match File::open(index_path(&self.path)) {
+                Ok(file) => load_index(file)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    log::info!("no index for {}, rebuilding", self.path.display());
+                    self.rebuild_index()?
+                }
+                Err(err) => return Err(err),
+            };
+            self.index = Some(index);
+        }
+        Ok(self.index.as_deref().expect("loaded above"))
+    }
+
+    fn rebuild_index(&mut self) -> io::Result<Vec<IndexEntry>> {
+        let position = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut index = vec![];
+        loop {
+            let offset = self.file.stream_position()?;
+            match self.read_message()? {
+                Some(msg) => index.push(IndexEntry {
+                    timestamp: msg.timestamp,
+                    offset,
+                }),
+                None => break,
+            }
+        }
+        self.file.seek(SeekFrom::Start(position))?;
+
+        let mut file = BufWriter::new(File::create(index_path(&self.path))?);
+        for entry in &index {
+            file.write_all(&nanos(entry.timestamp).to_le_bytes())?;
+            file.write_all(&entry.offset.to_le_bytes())?;
+        }
+        file.flush()?;
+        Ok(index)
+    }
+
+    /// Continue reading from the message number `n`.
+    pub fn seek(&mut self, n: usize) -> io::Result<()> {
+        let offset = match self.index()?.get(n).map(|entry| entry.offset) {
+            Some(offset) => offset,
+            None => self.file.get_ref().metadata()?.len(),
+        };
+        self.file.seek(SeekFrom::Start(offset)).map(drop)
+    }
+
+    /// Continue reading from the first message received at or after the `timestamp`.
+    pub fn seek_time(&mut self, timestamp: SystemTime) -> io::Result<()> {
+        let n = self
+            .index()?
+            .partition_point(|entry| entry.timestamp < timestamp);
+        self.seek(n)
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Message>> {
+        let mut timestamp = [0; 1];
+        match self.file.read_exact(&mut timestamp) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        match self.read_record(timestamp[0]) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                log::warn!("{}: the last record is truncated", self.path.display());
+                Ok(None)
+            }
+            res => res.map(Some),
+        }
+    }
+
+    /// The rest of the record after the first byte.
+    fn read_record(&mut self, first: u8) -> io::Result<Message> {
+        let mut timestamp = [0; 8];
+        timestamp[0] = first;
+        self.file.read_exact(&mut timestamp[1..])?;
+        let timestamp =
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::from_le_bytes(timestamp));
+
+        let source = match self.read_bytes(1)? {
+            source if source.is_empty() => None,
+            source => {
+                Some(PeerId::from_bytes(&source).map_err(|err| invalid_data(err.to_string()))?)
+            }
+        };
+        let id = self.read_bytes(2)?;
+        let mut present = [0; 1];
+        self.file.read_exact(&mut present)?;
+        let sequence_number = if present[0] != 0 {
+            let mut sequence_number = [0; 8];
+            self.file.read_exact(&mut sequence_number)?;
+            Some(u64::from_le_bytes(sequence_number))
+        } else {
+            None
+        };
+        let topic =
+            String::from_utf8(self.read_bytes(2)?).map_err(|err| invalid_data(err.to_string()))?;
+        let data = self.read_bytes(4)?;
+
+        Ok(Message {
+            timestamp,
+            source,
+            id,
+            sequence_number,
+            topic,
+            data,
+        })
+    }
+
+    /// Read the length of `len_size` bytes, then as many bytes.
+    fn read_bytes(&mut self, len_size: usize) -> io::Result<Vec<u8>> {
+        let mut len = [0; 8];
+        self.file.read_exact(&mut len[..len_size])?;
+        let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+fn load_index(file: File) -> io::Result<Vec<IndexEntry>> {
+    let mut file = BufReader::new(file);
+    let mut index = vec![];
+    let mut entry = [0; 16];
+    loop {
+        match file.read_exact(&mut entry) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            res => res?,
+        }
+        let timestamp = u64::from_le_bytes(entry[..8].try_into().expect("cannot fail"));
+        index.push(IndexEntry {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_nanos(timestamp),
+            offset: u64::from_le_bytes(entry[8..].try_into().expect("cannot fail")),
+        });
+    }
+    Ok(index)
+}
+
+/// Read the old format: the gossipsub message data concatenated, nothing else.
+/// The data starts with its own 8 bytes length, so the frames can be split.
+/// The messages get the timestamps `start`, `start + interval`, and so on.
+pub fn import_raw<R>(
+    mut raw: R,
+    topic: &str,
+    start: SystemTime,
+    interval: Duration,
+    writer: &mut RecordingWriter,
+) -> io::Result<usize>
+where
+    R: Read,
+{
+    let mut len = [0; 8];
+    let mut count = 0;
+    loop {
+        match raw.read_exact(&mut len) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            res => res?,
+        }
+        let mut data = vec![0; 8 + u64::from_le_bytes(len) as usize];
+        data[..8].clone_from_slice(&len);
+        raw.read_exact(&mut data[8..])?;
+        writer.write(&Message {
+            timestamp: start + interval * count as u32,
+            source: None,
+            id: vec![],
+            sequence_number: None,
+            topic: topic.to_owned(),
+            data,
+        })?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    use libp2p::PeerId;
+
+    use super::{Message, RecordingReader, RecordingWriter};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("recording-{}-{name}", std::process::id()))
+    }
+
+    fn messages() -> Vec<Message> {
+        (0..3)
+            .map(|i| Message {
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + i),
+                source: (i != 1).then(PeerId::random),
+                id: vec![i as u8; 20],
+                sequence_number: (i != 2).then_some(i),
+                topic: "coda/consensus-messages/0.0.1".to_owned(),
+                data: vec![i as u8; 100 * i as usize],
+            })
+            .collect()
+    }
+
+    fn write(path: &Path, messages: &[Message]) {
+        let mut writer = RecordingWriter::create(path).unwrap();
+        for msg in messages {
+            writer.write(msg).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    fn assert_same(read: &[Message], written: &[Message]) {
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written) {
+            assert_eq!(read.timestamp, written.timestamp);
+            assert_eq!(read.source, written.source);
+            assert_eq!(read.id, written.id);
+            assert_eq!(read.sequence_number, written.sequence_number);
+            assert_eq!(read.topic, written.topic);
+            assert_eq!(read.data, written.data);
+        }
+    }
+
+    #[test]
+    fn write_read() {
+        let path = temp_path("full");
+        let written = messages();
+        write(&path, &written);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.index().unwrap().len(), written.len());
+        let read = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_same(&read, &written);
+
+        reader.seek(2).unwrap();
+        let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_same(&read, &written[2..]);
+    }
+
+    #[test]
+    fn read_truncated() {
+        let path = temp_path("truncated");
+        let written = messages();
+        write(&path, &written);
+        let mut bytes = fs::read(&path).unwrap();
+        // cut the last record in the middle of its payload
+        bytes.truncate(bytes.len() - 50);
+        fs::write(&path, bytes).unwrap();
+        // the index is rebuilt from what is left
+        fs::remove_file(path.with_extension("idx")).unwrap();
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.index().unwrap().len(), written.len() - 1);
+        let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_same(&read, &written[..written.len() - 1]);
+    }
+}
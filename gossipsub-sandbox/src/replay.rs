@@ -0,0 +1,113 @@
+use std::time::{Duration, SystemTime};
+
+use libp2p::gossipsub::{IdentTopic, PublishError};
+use tokio::time::Instant;
+
+use super::{message::Kind, node::Node, recording::RecordingReader, transform::Transform};
+
+/// How long go-libp2p-pubsub remembers the seen message ids (`TimeCacheDuration`),
+/// longer than the duplicate cache of rust-libp2p. The same message published
+/// again within this time is dropped as a duplicate.
+const SEEN_MESSAGES_TTL: Duration = Duration::from_secs(120);
+
+pub struct ReplayOptions {
+    /// Wait until the mesh of the topic has this many peers.
+    pub min_mesh: usize,
+    /// `2.0` publishes twice as fast as the messages were recorded.
+    pub speed: f64,
+    /// Start again from `start` when the end is reached. The messages are the same
+    /// in every pass, so a pass must be longer than `SEEN_MESSAGES_TTL`.
+    pub repeat: bool,
+    /// Offset from the first message of the recording.
+    pub start: Duration,
+    /// Offset from the first message of the recording.
+    pub end: Option<Duration>,
+    /// Only these kinds, all if empty.
+    pub kind: Vec<Kind>,
+    /// Applied to every message before it is published.
+    pub transform: Transform,
+}
+
+/// Publish the messages with the same intervals between them as they were received.
+pub async fn run(
+    node: &mut Node,
+    mut reader: RecordingReader,
+    topic: &IdentTopic,
+    options: ReplayOptions,
+) {
+    let index = reader.index().unwrap();
+    let Some(first) = index.first().map(|entry| entry.timestamp) else {
+        log::warn!("the recording is empty");
+        return;
+    };
+    let start = first + options.start;
+    let end = options.end.map(|end| first + end);
+
+    if options.repeat {
+        let mut replayed = index
+            .iter()
+            .map(|entry| entry.timestamp)
+            .filter(|timestamp| *timestamp >= start && end.map_or(true, |end| *timestamp <= end));
+        let pass_start = replayed.next().unwrap_or(start);
+        let pass_end = replayed.last().unwrap_or(pass_start);
+        let pass = pass_end
+            .duration_since(pass_start)
+            .unwrap_or_default()
+            .div_f64(options.speed);
+        if pass < SEEN_MESSAGES_TTL {
+            log::error!(
+                "a pass takes {pass:?}, the peers would drop the repeated messages \
+                 as duplicates for {SEEN_MESSAGES_TTL:?}, replay more or slower to loop"
+            );
+            return;
+        }
+    }
+
+    node.wait_for_mesh(&topic.hash(), options.min_mesh).await;
+
+    loop {
+        reader.seek_time(start).unwrap();
+        // the recorded time and the local time when the replay started
+        let mut origin = None::<(SystemTime, Instant)>;
+        let mut published = 0;
+        let mut dropped = 0;
+        let mut duplicates = 0;
+        for msg in &mut reader {
+            let msg = msg.unwrap();
+            if end.map_or(false, |end| msg.timestamp > end) {
+                break;
+            }
+            if !options.kind.is_empty()
+                && !Kind::of_frame(&msg.data).map_or(false, |kind| options.kind.contains(&kind))
+            {
+                continue;
+            }
+            let Some(data) = options.transform.apply(msg.data) else {
+                dropped += 1;
+                continue;
+            };
+
+            let (recorded, local) = *origin.get_or_insert((msg.timestamp, Instant::now()));
+            let offset = msg
+                .timestamp
+                .duration_since(recorded)
+                .unwrap_or_default()
+                .div_f64(options.speed);
+            node.run_until(local + offset).await;
+
+            match node.gossipsub().publish(topic.clone(), data) {
+                Ok(_) => published += 1,
+                Err(PublishError::Duplicate) => duplicates += 1,
+                Err(err) => log::warn!("cannot publish: {err:?}"),
+            }
+        }
+        log::info!("published {published} messages, dropped {dropped}, {duplicates} duplicates");
+        if !options.repeat {
+            break;
+        }
+    }
+
+    // let the last messages go out
+    node.run_until(Instant::now() + Duration::from_secs(5))
+        .await;
+}
@@ -0,0 +1,110 @@
+//! Gossip made of the blocks that bootstrap-sandbox recorded.
+//! The recording has the blocks in `blocks/<height>/<state_hash>`
+//! and `blocks/table.json` that maps the state hash to the height.
+
+use std::{collections::BTreeMap, fs::File, path::Path, time::Duration};
+
+use binprot::BinProtRead;
+use libp2p::gossipsub::IdentTopic;
+use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+use tokio::time::Instant;
+
+use super::{message, node::Node};
+
+pub struct SynthesizeOptions {
+    /// Wait until the mesh of the topic has this many peers.
+    pub min_mesh: usize,
+    /// The fixed time between the blocks,
+    /// by default the difference of their timestamps, so the slot time.
+    pub interval: Option<Duration>,
+    /// `2.0` publishes twice as fast.
+    pub speed: f64,
+}
+
+/// The longest chain of the recording, from the lowest block to the highest.
+pub fn load_chain<P>(path: P) -> Vec<v2::MinaBlockBlockStableV2>
+where
+    P: AsRef<Path>,
+{
+    let path_blocks = path.as_ref().join("blocks");
+    let file = File::open(path_blocks.join("table.json")).unwrap();
+    let table = serde_json::from_reader::<_, BTreeMap<String, u32>>(file).unwrap();
+
+    let read = |hash: &str, height: u32| {
+        let path = path_blocks.join(height.to_string()).join(hash);
+        let mut file = File::open(path).ok()?;
+        v2::MinaBlockBlockStableV2::binprot_read(&mut file).ok()
+    };
+
+    // start from the highest block and follow the parents
+    let mut chain = vec![];
+    let mut next = table
+        .iter()
+        .max_by_key(|(_, height)| **height)
+        .map(|(hash, height)| (hash.clone(), *height));
+    while let Some((hash, height)) = next.take() {
+        let Some(block) = read(&hash, height) else {
+            log::warn!("cannot read the block {hash} at {height}");
+            break;
+        };
+        let previous = block.header.protocol_state.previous_state_hash.to_string();
+        next = table
+            .get(&previous)
+            .map(|height| (previous.clone(), *height));
+        chain.push(block);
+    }
+    chain.reverse();
+    chain
+}
+
+/// Milliseconds since unix epoch, the beginning of the slot of the block.
+fn timestamp(block: &v2::MinaBlockBlockStableV2) -> Option<u64> {
+    let timestamp = &block.header.protocol_state.body.blockchain_state.timestamp;
+    match serde_json::to_value(timestamp).ok()? {
+        serde_json::Value::String(s) => s.parse().ok(),
+        value => value.as_u64(),
+    }
+}
+
+/// Publish the blocks as `GossipNetMessageV2::NewState`.
+pub async fn run(
+    node: &mut Node,
+    chain: Vec<v2::MinaBlockBlockStableV2>,
+    topic: &IdentTopic,
+    options: SynthesizeOptions,
+) {
+    node.wait_for_mesh(&topic.hash(), options.min_mesh).await;
+
+    let mut previous_timestamp = None;
+    let mut due = Instant::now();
+    for block in chain {
+        let this_timestamp = timestamp(&block);
+        let interval = match (options.interval, previous_timestamp, this_timestamp) {
+            (Some(interval), _, _) => interval,
+            (None, Some(previous), Some(this)) => {
+                Duration::from_millis(u64::saturating_sub(this, previous))
+            }
+            _ => Duration::ZERO,
+        };
+        previous_timestamp = this_timestamp;
+        due += interval.div_f64(options.speed);
+        node.run_until(due).await;
+
+        let protocol_state = &block.header.protocol_state;
+        let height = protocol_state
+            .body
+            .consensus_state
+            .blockchain_length
+            .as_u32();
+        let hash = protocol_state.hash();
+        let data = message::encode(&GossipNetMessageV2::NewState(block));
+        match node.gossipsub().publish(topic.clone(), data) {
+            Ok(_) => log::info!("published block {height} {hash}"),
+            Err(err) => log::warn!("cannot publish block {height} {hash}: {err:?}"),
+        }
+    }
+
+    // let the last block go out
+    node.run_until(Instant::now() + Duration::from_secs(5))
+        .await;
+}
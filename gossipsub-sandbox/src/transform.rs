@@ -0,0 +1,154 @@
+//! Re-target the recorded snark work to another network before it is replayed.
+//! The proof is bound to the fee and the prover, so the rewritten work
+//! only passes a snark pool that does not verify the proofs.
+
+use std::{collections::BTreeSet, fs::File, path::Path};
+
+use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+use serde::de::DeserializeOwned;
+
+use super::{inspect, message};
+
+pub struct Transform {
+    /// The fee in nanomina.
+    fee: Option<v2::CurrencyFeeStableV1>,
+    prover: Option<v2::NonZeroCurvePoint>,
+    /// Keep only the work that starts from these ledgers.
+    ledgers: Option<BTreeSet<String>>,
+}
+
+/// The value from its JSON string, hashes and public keys are base58 there.
+fn from_json_string<T>(s: &str) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_value(serde_json::Value::String(s.to_owned()))
+        .ok()
+        .or_else(|| serde_json::from_str(s).ok())
+}
+
+impl Transform {
+    /// `ledgers` is a JSON file with the list of the ledger hashes the local chain has.
+    /// Fails if the fee, the prover or the file is not usable.
+    pub fn new<P>(
+        fee: Option<u64>,
+        prover: Option<String>,
+        ledgers: Option<P>,
+    ) -> Result<Self, String>
+    where
+        P: AsRef<Path>,
+    {
+        let fee = fee
+            .map(|fee| {
+                from_json_string(&fee.to_string())
+                    .ok_or_else(|| format!("cannot use {fee} as the fee"))
+            })
+            .transpose()?;
+        let prover = prover
+            .map(|prover| {
+                from_json_string(&prover).ok_or_else(|| format!("{prover} is not a public key"))
+            })
+            .transpose()?;
+        let ledgers = ledgers
+            .map(|path| {
+                let path = path.as_ref();
+                let file = File::open(path)
+                    .map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+                serde_json::from_reader(file)
+                    .map_err(|err| format!("cannot parse {}: {err}", path.display()))
+            })
+            .transpose()?;
+        Ok(Transform {
+            fee,
+            prover,
+            ledgers,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fee.is_none() && self.prover.is_none() && self.ledgers.is_none()
+    }
+
+    /// The new message data, `None` if the work must be dropped.
+    /// The other kinds and the data that does not decode are left as they are.
+    pub fn apply(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.is_empty() || message::Kind::of_frame(&data) != Some(message::Kind::SnarkPoolDiff) {
+            return Some(data);
+        }
+        let Ok(mut msg) = message::decode(&data) else {
+            return Some(data);
+        };
+
+        if let Some(ledgers) = &self.ledgers {
+            let work_ids = inspect::summarize(&msg).work_ids;
+            let stale = work_ids.iter().any(|id| {
+                let source = id.split("->").next().unwrap_or_default();
+                !ledgers.contains(source)
+            });
+            if stale {
+                log::debug!("drop stale work {}", work_ids.join(" "));
+                return None;
+            }
+        }
+
+        if let GossipNetMessageV2::SnarkPoolDiff(
+            v2::NetworkPoolSnarkPoolDiffVersionedStableV2::AddSolvedWork(work),
+        ) = &mut msg
+        {
+            let (_, solution) = work.as_mut();
+            if let Some(fee) = &self.fee {
+                solution.fee.fee = fee.clone();
+            }
+            if let Some(prover) = &self.prover {
+                solution.fee.prover = prover.clone();
+            }
+        }
+        Some(message::encode(&msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, path::PathBuf};
+
+    use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+
+    use super::Transform;
+    use crate::message;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn new_rejects_bad_config() {
+        let no_file = None::<PathBuf>;
+        assert!(Transform::new(None, Some("not a key".to_owned()), no_file).is_err());
+        let missing = Some(PathBuf::from("/nonexistent/ledgers.json"));
+        assert!(Transform::new(None, None, missing).is_err());
+        let transform = Transform::new(None, None, None::<PathBuf>).unwrap();
+        assert!(transform.is_empty());
+    }
+
+    #[test]
+    fn apply_leaves_what_it_does_not_rewrite() {
+        let transform = Transform {
+            fee: None,
+            prover: None,
+            ledgers: Some(BTreeSet::new()),
+        };
+        // another kind
+        let data = frame(&[2, 0]);
+        assert_eq!(transform.apply(data.clone()), Some(data));
+        // a snark pool diff that does not decode
+        let data = frame(&[1, 0xff, 0xff]);
+        assert_eq!(transform.apply(data.clone()), Some(data));
+        // a snark pool diff without work, no ledger to check
+        let msg =
+            GossipNetMessageV2::SnarkPoolDiff(v2::NetworkPoolSnarkPoolDiffVersionedStableV2::Empty);
+        let data = message::encode(&msg);
+        assert_eq!(transform.apply(data.clone()), Some(data));
+    }
+}
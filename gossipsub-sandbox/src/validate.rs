@@ -0,0 +1,298 @@
+use std::{collections::BTreeMap, fs::File, io, path::Path};
+
+use libp2p::{gossipsub::MessageAcceptance, PeerId};
+use mina_p2p_messages::{gossip::GossipNetMessageV2, v2};
+use serde::Serialize;
+
+use super::{inspect, message};
+
+/// Blocks further than this below the best seen block are not interesting anymore,
+/// the `k` of the consensus.
+const STALE_DEPTH: u32 = 290;
+
+/// Without a best height yet, a block sets it only if this many of its ancestors
+/// in a row are seen too, a forged block or two cannot claim the chain is far ahead.
+const CONFIRMATIONS: u32 = 3;
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct PeerStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub ignored: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_rejection: Option<String>,
+}
+
+enum Verdict {
+    Accept,
+    Reject(String),
+    Ignore(String),
+}
+
+/// Checks the messages before gossipsub forwards them,
+/// for the gossipsub config with `validate_messages`.
+/// The checks are the ones possible without the chain: the message parses,
+/// the block is consistent with itself and with the blocks seen before,
+/// the snark work has a sane fee. The proofs are not verified, so the best
+/// height only follows the blocks that extend a block seen before,
+/// and only by a bounded jump, the first one needs `CONFIRMATIONS`.
+pub struct Validator {
+    max_snark_fee: u64,
+    genesis_state_hash: Option<String>,
+    best_height: u32,
+    /// The blocks seen recently by state hash.
+    seen: BTreeMap<String, SeenBlock>,
+    peers: BTreeMap<PeerId, PeerStats>,
+}
+
+#[derive(Clone, Copy)]
+struct SeenBlock {
+    height: u32,
+    /// How many ancestors in a row are seen too.
+    ancestors: u32,
+}
+
+impl Validator {
+    /// `max_snark_fee` in nanomina. The blocks of another chain than
+    /// `genesis_state_hash` are rejected, any chain is accepted without it.
+    pub fn new(max_snark_fee: u64, genesis_state_hash: Option<String>) -> Self {
+        if genesis_state_hash.is_none() {
+            log::warn!("no genesis state hash, the blocks of any chain are accepted");
+        }
+        Validator {
+            max_snark_fee,
+            genesis_state_hash,
+            best_height: 0,
+            seen: BTreeMap::new(),
+            peers: BTreeMap::new(),
+        }
+    }
+
+    /// Decide what gossipsub should do with the message and count it for the peer.
+    /// Returns the message if it parses.
+    pub fn validate(
+        &mut self,
+        source: PeerId,
+        data: &[u8],
+    ) -> (MessageAcceptance, Option<GossipNetMessageV2>) {
+        let (verdict, msg) = match message::decode(data) {
+            Ok(msg) => (self.check(&msg), Some(msg)),
+            Err(err) => (Verdict::Reject(format!("cannot decode: {err}")), None),
+        };
+
+        let stats = self.peers.entry(source).or_default();
+        let acceptance = match verdict {
+            Verdict::Accept => {
+                stats.accepted += 1;
+                MessageAcceptance::Accept
+            }
+            Verdict::Reject(reason) => {
+                log::warn!("reject the message from {source}: {reason}");
+                stats.rejected += 1;
+                stats.last_rejection = Some(reason);
+                MessageAcceptance::Reject
+            }
+            Verdict::Ignore(reason) => {
+                log::debug!("ignore the message from {source}: {reason}");
+                stats.ignored += 1;
+                MessageAcceptance::Ignore
+            }
+        };
+        (acceptance, msg)
+    }
+
+    fn check(&mut self, msg: &GossipNetMessageV2) -> Verdict {
+        match msg {
+            GossipNetMessageV2::NewState(block) => self.check_block(block),
+            GossipNetMessageV2::SnarkPoolDiff(
+                v2::NetworkPoolSnarkPoolDiffVersionedStableV2::Empty,
+            ) => Verdict::Ignore("empty snark pool diff".to_owned()),
+            GossipNetMessageV2::SnarkPoolDiff(_) => self.check_snark(inspect::summarize(msg)),
+            GossipNetMessageV2::TransactionPoolDiff(diff) => {
+                if diff.0.is_empty() {
+                    Verdict::Ignore("no transactions".to_owned())
+                } else {
+                    Verdict::Accept
+                }
+            }
+        }
+    }
+
+    fn check_block(&mut self, block: &v2::MinaBlockBlockStableV2) -> Verdict {
+        let protocol_state = &block.header.protocol_state;
+        let height = protocol_state
+            .body
+            .consensus_state
+            .blockchain_length
+            .as_u32();
+        let hash = protocol_state.hash().to_string();
+        let previous_hash = protocol_state.previous_state_hash.to_string();
+        let genesis_hash = protocol_state.body.genesis_state_hash.to_string();
+
+        if height <= 1 {
+            return Verdict::Reject(format!("block {hash} has the height {height}"));
+        }
+        if hash == previous_hash {
+            return Verdict::Reject(format!("block {hash} is its own parent"));
+        }
+        if let Some(expected) = &self.genesis_state_hash {
+            if *expected != genesis_hash {
+                return Verdict::Reject(format!(
+                    "block {hash} is on the chain {genesis_hash}, expected {expected}"
+                ));
+            }
+        }
+        // with `delta` zero the proof is the parent alone, a longer one
+        // would need the body hashes of the ancestors to check
+        let (proof_start, proof_body_hashes) = &block.header.delta_block_chain_proof;
+        if proof_body_hashes.is_empty() && proof_start.to_string() != previous_hash {
+            return Verdict::Reject(format!(
+                "block {hash} has the delta chain proof from {proof_start}, not from its parent"
+            ));
+        }
+        self.check_chain(hash, &previous_hash, height)
+    }
+
+    /// The place of the block in the chain, by the blocks seen before.
+    fn check_chain(&mut self, hash: String, previous_hash: &str, height: u32) -> Verdict {
+        let parent = self.seen.get(previous_hash).copied();
+        if let Some(parent) = parent {
+            if parent.height.saturating_add(1) != height {
+                return Verdict::Reject(format!(
+                    "block {hash} at {height} extends {previous_hash} at {}",
+                    parent.height
+                ));
+            }
+        }
+        if height.saturating_add(STALE_DEPTH) < self.best_height {
+            return Verdict::Ignore(format!("block {hash} at {height} is stale"));
+        }
+        let ancestors = parent.map_or(0, |parent| parent.ancestors.saturating_add(1));
+        let block = SeenBlock { height, ancestors };
+        if self.seen.insert(hash.clone(), block).is_some() {
+            return Verdict::Ignore(format!("block {hash} is already seen"));
+        }
+
+        // a block with an unknown parent is not verified by anything, it could
+        // claim any height; the ones extending it are not much better,
+        // so the first best height needs a few of them in a row
+        // and then a block moves it by at most `STALE_DEPTH`
+        let moves = match self.best_height {
+            0 => ancestors >= CONFIRMATIONS,
+            best_height => {
+                height > best_height && height <= best_height.saturating_add(STALE_DEPTH)
+            }
+        };
+        if moves {
+            self.best_height = height;
+            let min_height = self.best_height.saturating_sub(STALE_DEPTH);
+            self.seen.retain(|_, block| block.height >= min_height);
+        }
+        Verdict::Accept
+    }
+
+    fn check_snark(&self, summary: inspect::Summary) -> Verdict {
+        if summary.work_ids.is_empty() {
+            return Verdict::Reject("snark work without statements".to_owned());
+        }
+        let fee = summary.fee.unwrap_or_default();
+        match fee.parse::<u64>() {
+            Ok(fee) if fee > self.max_snark_fee => Verdict::Reject(format!(
+                "snark work fee {fee} is above {}",
+                self.max_snark_fee
+            )),
+            Ok(_) => Verdict::Accept,
+            Err(_) => Verdict::Reject(format!("snark work fee {fee} is not a number")),
+        }
+    }
+
+    /// Log the peers that sent something wrong and write the stats of every peer as JSON.
+    pub fn report<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        for (peer_id, stats) in &self.peers {
+            if stats.rejected > 0 {
+                log::info!(
+                    "{peer_id} rejected {} of {}, last: {}",
+                    stats.rejected,
+                    stats.accepted + stats.rejected + stats.ignored,
+                    stats.last_rejection.as_deref().unwrap_or_default(),
+                );
+            }
+        }
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, stats)| (peer_id.to_string(), stats))
+            .collect::<BTreeMap<_, _>>();
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &peers).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Validator, Verdict, CONFIRMATIONS};
+
+    fn check(validator: &mut Validator, hash: &str, previous_hash: &str, height: u32) -> Verdict {
+        validator.check_chain(hash.to_owned(), previous_hash, height)
+    }
+
+    #[test]
+    fn forged_high_blocks_do_not_set_the_best_height() {
+        let mut validator = Validator::new(0, None);
+        // a forged pair claiming the chain is far ahead
+        let forged = 1_000_000;
+        assert!(matches!(
+            check(&mut validator, "f0", "x", forged),
+            Verdict::Accept
+        ));
+        assert!(matches!(
+            check(&mut validator, "f1", "f0", forged + 1),
+            Verdict::Accept
+        ));
+        assert_eq!(validator.best_height, 0);
+
+        // the real chain is still accepted and sets the best height once confirmed
+        let real = 5000;
+        assert!(matches!(
+            check(&mut validator, "r0", "g", real),
+            Verdict::Accept
+        ));
+        for i in 1..=CONFIRMATIONS {
+            let (hash, previous_hash) = (format!("r{i}"), format!("r{}", i - 1));
+            let verdict = check(&mut validator, &hash, &previous_hash, real + i);
+            assert!(matches!(verdict, Verdict::Accept));
+        }
+        assert_eq!(validator.best_height, real + CONFIRMATIONS);
+
+        // extending the forged pair no longer moves it that far
+        assert!(matches!(
+            check(&mut validator, "f2", "f1", forged + 2),
+            Verdict::Accept
+        ));
+        assert_eq!(validator.best_height, real + CONFIRMATIONS);
+        assert!(matches!(
+            check(&mut validator, "old", "y", real - 1000),
+            Verdict::Ignore(_)
+        ));
+    }
+
+    #[test]
+    fn wrong_height_of_the_parent() {
+        let mut validator = Validator::new(0, None);
+        assert!(matches!(
+            check(&mut validator, "a", "x", 10),
+            Verdict::Accept
+        ));
+        assert!(matches!(
+            check(&mut validator, "b", "a", 12),
+            Verdict::Reject(_)
+        ));
+        assert!(matches!(
+            check(&mut validator, "a", "x", 10),
+            Verdict::Ignore(_)
+        ));
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf, time::SystemTime};
+
+use binprot::BinProtRead;
+use mina_p2p_messages::{
+    rpc::{
+        GetBestTipV2, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
+        AnswerSyncLedgerQueryV2, GetTransitionChainV2, GetTransitionChainProofV1ForV2,
+        GetTransitionKnowledgeV1ForV2, GetSomeInitialPeersV1ForV2,
+    },
+    rpc_kernel::{RpcMethod, MessageHeader, QueryPayload, ResponsePayload},
+};
+use mina_transport::{
+    capture::{CaptureReader, YamuxDemux},
+    wire::{self, YamuxFrameType, yamux_flags},
+};
+use serde::Serialize;
+use structopt::StructOpt;
+
+const RPC_PROTOCOL: &str = "coda/rpcs/0.0.1";
+
+/// Decode the file written by `mina_transport::capture::FileCapture`.
+/// Prints one JSON line per Mina RPC message, or per yamux frame with `--frames`.
+#[derive(StructOpt)]
+struct Args {
+    path: PathBuf,
+    /// Print the yamux frames instead of the RPC messages.
+    #[structopt(long)]
+    frames: bool,
+}
+
+fn main() {
+    let Args { path, frames } = Args::from_args();
+
+    let file = BufReader::new(File::open(path).unwrap());
+    let reader = CaptureReader::new(file).unwrap();
+    let mut demux = YamuxDemux::default();
+    let mut streams = BTreeMap::<(u64, u32, bool), Stream>::new();
+    let mut queries = BTreeMap::<(u64, u32, bool, i64), (String, i32)>::new();
+
+    for record in reader {
+        for frame in demux.push(record.unwrap()).unwrap() {
+            if frames {
+                let line = serde_json::json!({
+                    "timestamp": frame.timestamp,
+                    "connection_id": frame.connection_id,
+                    "peer_id": frame.peer_id.to_string(),
+                    "incoming": frame.incoming,
+                    "type": format!("{:?}", frame.ty),
+                    "flags": frame.flags,
+                    "stream_id": frame.stream_id,
+                    "length": frame.length,
+                });
+                println!("{line}");
+                continue;
+            }
+            let key = (frame.connection_id, frame.stream_id, frame.incoming);
+            if frame.flags & yamux_flags::RST != 0 {
+                streams.remove(&key);
+                streams.remove(&(frame.connection_id, frame.stream_id, !frame.incoming));
+                continue;
+            }
+            if frame.ty != YamuxFrameType::Data {
+                continue;
+            }
+            let stream = streams.entry(key).or_default();
+            stream.buffer.extend_from_slice(&frame.data);
+            while let Some(message) = stream.next_message() {
+                let mut bytes = message.as_slice();
+                let header = match MessageHeader::binprot_read(&mut bytes) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        log(&frame, "error", serde_json::json!(err.to_string()));
+                        continue;
+                    }
+                };
+                match header {
+                    MessageHeader::Heartbeat => {}
+                    MessageHeader::Query(header) => {
+                        let tag = header.tag.to_string_lossy();
+                        let version = header.version as i32;
+                        let payload = decode(&tag, version, true, bytes);
+                        let id = header.id;
+                        let q = (frame.connection_id, frame.stream_id, frame.incoming, id);
+                        queries.insert(q, (tag.clone(), version));
+                        let value = serde_json::json!({
+                            "type": "query", "tag": tag, "version": version, "id": id,
+                            "query": payload,
+                        });
+                        log(&frame, "rpc", value);
+                    }
+                    MessageHeader::Response(header) => {
+                        let id = header.id;
+                        let q = (frame.connection_id, frame.stream_id, !frame.incoming, id);
+                        let Some((tag, version)) = queries.remove(&q) else {
+                            log(
+                                &frame,
+                                "error",
+                                serde_json::json!(format!("unknown id {id}")),
+                            );
+                            continue;
+                        };
+                        let payload = decode(&tag, version, false, bytes);
+                        let value = serde_json::json!({
+                            "type": "response", "tag": tag, "version": version, "id": id,
+                            "response": payload,
+                        });
+                        log(&frame, "rpc", value);
+                    }
+                }
+            }
+            if frame.flags & yamux_flags::FIN != 0 {
+                streams.remove(&key);
+            }
+        }
+    }
+}
+
+fn log(frame: &mina_transport::capture::YamuxFrame, kind: &str, value: serde_json::Value) {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        timestamp: SystemTime,
+        connection_id: u64,
+        peer_id: String,
+        incoming: bool,
+        stream_id: u32,
+        kind: &'a str,
+        message: serde_json::Value,
+    }
+
+    let line = Line {
+        timestamp: frame.timestamp,
+        connection_id: frame.connection_id,
+        peer_id: frame.peer_id.to_string(),
+        incoming: frame.incoming,
+        stream_id: frame.stream_id,
+        kind,
+        message: value,
+    };
+    println!("{}", serde_json::to_string(&line).unwrap());
+}
+
+/// One direction of a yamux stream.
+#[derive(Default)]
+struct Stream {
+    buffer: Vec<u8>,
+    protocol: Option<String>,
+    handshake_done: bool,
+}
+
+impl Stream {
+    /// Skip multistream-select negotiation and the RPC handshake,
+    /// return the next complete RPC message without its length prefix.
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        while self.protocol.is_none() {
+            let (line, len) = wire::multistream_message(&self.buffer)?;
+            self.buffer.drain(..len);
+            if wire::is_protocol(&line) {
+                self.protocol = Some(line);
+            }
+        }
+        if self.protocol.as_deref() != Some(RPC_PROTOCOL) {
+            self.buffer.clear();
+            return None;
+        }
+
+        loop {
+            if self.buffer.len() < 8 {
+                return None;
+            }
+            let len = u64::from_le_bytes(self.buffer[..8].try_into().unwrap()) as usize;
+            if self.buffer.len() < 8 + len {
+                return None;
+            }
+            let message = self.buffer[8..(8 + len)].to_vec();
+            self.buffer.drain(..(8 + len));
+            if self.handshake_done {
+                return Some(message);
+            }
+            self.handshake_done = true;
+        }
+    }
+}
+
+fn decode(tag: &str, version: i32, query: bool, bytes: &[u8]) -> serde_json::Value {
+    fn inner<M>(query: bool, mut bytes: &[u8]) -> serde_json::Value
+    where
+        M: RpcMethod,
+        M::Query: Serialize,
+        M::Response: Serialize,
+    {
+        let res = if query {
+            QueryPayload::<M::Query>::binprot_read(&mut bytes)
+                .map(|payload| serde_json::to_value(payload.0).unwrap())
+        } else {
+            ResponsePayload::<M::Response>::binprot_read(&mut bytes).map(|payload| match payload {
+                Ok(v) => serde_json::to_value(v.0).unwrap(),
+                Err(err) => serde_json::json!({ "error": format!("{err:?}") }),
+            })
+        };
+        res.unwrap_or_else(|err| serde_json::json!({ "decode_error": err.to_string() }))
+    }
+
+    match (tag, version) {
+        (GetBestTipV2::NAME, GetBestTipV2::VERSION) => inner::<GetBestTipV2>(query, bytes),
+        (GetAncestryV2::NAME, GetAncestryV2::VERSION) => inner::<GetAncestryV2>(query, bytes),
+        (AnswerSyncLedgerQueryV2::NAME, AnswerSyncLedgerQueryV2::VERSION) => {
+            inner::<AnswerSyncLedgerQueryV2>(query, bytes)
+        }
+        (
+            GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::NAME,
+            GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
+        ) => inner::<GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(query, bytes),
+        (GetTransitionChainV2::NAME, GetTransitionChainV2::VERSION) => {
+            inner::<GetTransitionChainV2>(query, bytes)
+        }
+        (GetTransitionChainProofV1ForV2::NAME, GetTransitionChainProofV1ForV2::VERSION) => {
+            inner::<GetTransitionChainProofV1ForV2>(query, bytes)
+        }
+        (GetTransitionKnowledgeV1ForV2::NAME, GetTransitionKnowledgeV1ForV2::VERSION) => {
+            inner::<GetTransitionKnowledgeV1ForV2>(query, bytes)
+        }
+        (GetSomeInitialPeersV1ForV2::NAME, GetSomeInitialPeersV1ForV2::VERSION) => {
+            inner::<GetSomeInitialPeersV1ForV2>(query, bytes)
+        }
+        _ => serde_json::json!({ "unknown": hex::encode(bytes) }),
+    }
+}
@@ -1,3 +1,7 @@
+use std::{fs::File, path::PathBuf};
+
+use mina_transport::chain_id::{self, GenesisConstants};
+use serde::Deserialize;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -14,6 +18,101 @@ enum Arg {
         #[structopt(long)]
         hash: String,
     },
+    /// Compute the chain id the way the Mina daemon does.
+    ChainId {
+        /// The runtime config (the `--config-file` of the daemon),
+        /// the constants are taken from its `genesis` and `daemon` sections.
+        #[structopt(long)]
+        config: PathBuf,
+        /// Base58 genesis state hash.
+        #[structopt(long)]
+        genesis_state_hash: String,
+        /// The md5 hex digests from `mina internal dump-constraint-system-digests`, in order.
+        #[structopt(long)]
+        constraint_system_digest: Vec<String>,
+        /// Mix the protocol versions into the chain id, like the newer daemons do.
+        #[structopt(long, requires = "protocol-network-version")]
+        protocol_transaction_version: Option<u32>,
+        #[structopt(long, requires = "protocol-transaction-version")]
+        protocol_network_version: Option<u32>,
+    },
+}
+
+/// Only the fields of the chain id. The missing ones take the values
+/// compiled into the daemon, the same as `GenesisConstants::default`.
+#[derive(Deserialize)]
+struct RuntimeConfig {
+    #[serde(default)]
+    genesis: RuntimeConfigGenesis,
+    #[serde(default)]
+    daemon: RuntimeConfigDaemon,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RuntimeConfigGenesis {
+    k: u32,
+    delta: u32,
+    slots_per_epoch: u32,
+    slots_per_sub_window: u32,
+    /// `None` keeps the compiled-in timestamp.
+    genesis_state_timestamp: Option<String>,
+}
+
+impl Default for RuntimeConfigGenesis {
+    fn default() -> Self {
+        let constants = GenesisConstants::default();
+        RuntimeConfigGenesis {
+            k: constants.k,
+            delta: constants.delta,
+            slots_per_epoch: constants.slots_per_epoch,
+            slots_per_sub_window: constants.slots_per_sub_window,
+            genesis_state_timestamp: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RuntimeConfigDaemon {
+    txpool_max_size: u32,
+}
+
+impl Default for RuntimeConfigDaemon {
+    fn default() -> Self {
+        RuntimeConfigDaemon {
+            txpool_max_size: GenesisConstants::default().txpool_max_size,
+        }
+    }
+}
+
+fn compute_chain_id(
+    config: PathBuf,
+    genesis_state_hash: String,
+    constraint_system_digests: Vec<String>,
+    protocol_versions: Option<(u32, u32)>,
+) -> String {
+    let file = File::open(config).unwrap();
+    let RuntimeConfig { genesis, daemon } = serde_json::from_reader(file).unwrap();
+    let constants = GenesisConstants {
+        k: genesis.k,
+        slots_per_epoch: genesis.slots_per_epoch,
+        slots_per_sub_window: genesis.slots_per_sub_window,
+        delta: genesis.delta,
+        txpool_max_size: daemon.txpool_max_size,
+        genesis_state_timestamp: match &genesis.genesis_state_timestamp {
+            Some(timestamp) => {
+                chain_id::parse_timestamp(timestamp).expect("bad genesis_state_timestamp")
+            }
+            None => GenesisConstants::default().genesis_state_timestamp,
+        },
+    };
+    chain_id::chain_id(
+        &genesis_state_hash,
+        &constraint_system_digests,
+        &constants,
+        protocol_versions,
+    )
 }
 
 fn main() {
@@ -22,6 +121,23 @@ fn main() {
         Arg::Ledger { hash } => (5, hash),
         Arg::State { hash } => (16, hash),
         Arg::PendingCoinbase { hash } => (12, hash),
+        Arg::ChainId {
+            config,
+            genesis_state_hash,
+            constraint_system_digest,
+            protocol_transaction_version,
+            protocol_network_version,
+        } => {
+            let protocol_versions = protocol_transaction_version.zip(protocol_network_version);
+            let id = compute_chain_id(
+                config,
+                genesis_state_hash,
+                constraint_system_digest,
+                protocol_versions,
+            );
+            println!("{id}");
+            return;
+        }
     };
     let x = if let Ok(mut bytes) = hex::decode(format!("{hash}01")) {
         bytes.reverse();
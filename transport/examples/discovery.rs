@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use libp2p::{swarm::SwarmEvent, Multiaddr};
+use mina_transport::{futures::StreamExt, Discovery};
+
+/// Several nodes on the memory transport, each knows only the first one.
+/// They should find each other through Kademlia.
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let chain_id = b"667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db";
+    let nodes = 6;
+
+    let mut seed = None::<Multiaddr>;
+    let mut tasks = vec![];
+    for i in 0..nodes {
+        let local_key = mina_transport::generate_identity();
+        let local_peer_id = local_key.public().to_peer_id();
+        let listen_on = format!("/memory/{}", 10000 + i)
+            .parse::<Multiaddr>()
+            .unwrap();
+        let peers = seed.iter().cloned().collect::<Vec<_>>();
+        seed.get_or_insert_with(|| format!("{listen_on}/p2p/{local_peer_id}").parse().unwrap());
+
+        let mut behaviour = Discovery::new(&local_key);
+        behaviour.add_seeds(&peers);
+        let mut swarm =
+            mina_transport::memory_swarm(local_key, chain_id, [listen_on], peers, behaviour)
+                .expect("failed to create the swarm");
+
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            let deadline = tokio::time::sleep(Duration::from_secs(10));
+            tokio::pin!(deadline);
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    _ = interval.tick() => swarm.behaviour_mut().bootstrap(),
+                    Some(event) = swarm.next() => {
+                        if let SwarmEvent::Behaviour(event) = event {
+                            if let Some(peer_id) = swarm.behaviour_mut().on_event(&event) {
+                                if !swarm.is_connected(&peer_id) {
+                                    if let Err(err) = swarm.dial(peer_id) {
+                                        log::warn!("node {i}: failed to dial {peer_id}: {err}");
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            let known = swarm.behaviour_mut().peers().len();
+            let connected = swarm.network_info().num_peers();
+            log::info!("node {i}: knows {known} peers, connected to {connected}");
+        }));
+    }
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use libp2p::swarm::SwarmEvent;
+use mina_transport::futures::{future, StreamExt};
+use mina_p2p_messages::rpc::GetBestTipV2;
+use libp2p_rpc_behaviour::{Event, BehaviourBuilder, Received};
+
+/// A client and a server in one process, talking over the memory transport.
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let chain_id = b"667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db";
+
+    let server_key = mina_transport::generate_identity();
+    let server_peer_id = server_key.public().to_peer_id();
+    let server_addr = "/memory/8302".parse().unwrap();
+    let behaviour = BehaviourBuilder::default()
+        .register_method::<GetBestTipV2>()
+        .build();
+    let mut server =
+        mina_transport::memory_swarm(server_key, chain_id, [server_addr], [], behaviour)
+            .expect("failed to create the server");
+
+    let client_key = mina_transport::generate_identity();
+    let server_addr = format!("/memory/8302/p2p/{server_peer_id}").parse().unwrap();
+    let behaviour = BehaviourBuilder::default().build();
+    let mut client =
+        mina_transport::memory_swarm(client_key, chain_id, [], [server_addr], behaviour)
+            .expect("failed to create the client");
+
+    let server = async move {
+        while let Some(event) = server.next().await {
+            if let SwarmEvent::Behaviour((
+                peer_id,
+                Event::Stream {
+                    stream_id,
+                    received: Received::Query { header, .. },
+                },
+            )) = event
+            {
+                log::info!("server: query {}", header.tag.to_string_lossy());
+                server
+                    .behaviour_mut()
+                    .respond::<GetBestTipV2>(peer_id, stream_id, header.id, Ok(None))
+                    .unwrap();
+            }
+        }
+    };
+
+    let client = async move {
+        while let Some(event) = client.next().await {
+            match event {
+                SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
+                    log::info!("client: connected to {peer_id}");
+                    client.behaviour_mut().open(peer_id, 0);
+                }
+                SwarmEvent::Behaviour((
+                    peer_id,
+                    Event::Stream {
+                        stream_id,
+                        received: Received::HandshakeDone,
+                    },
+                )) => {
+                    client
+                        .behaviour_mut()
+                        .query::<GetBestTipV2>(peer_id, stream_id, 1, ())
+                        .unwrap();
+                }
+                SwarmEvent::Behaviour((
+                    _,
+                    Event::Stream {
+                        received: Received::Response { header, bytes },
+                        ..
+                    },
+                )) => {
+                    log::info!("client: response {}, {} bytes", header.id, bytes.len());
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    future::select(Box::pin(server), Box::pin(client)).await;
+}
@@ -1,32 +1,16 @@
-use std::{
-    fs::File,
-    io::{Read, Write},
-};
+use std::path::Path;
 
 use libp2p::swarm::SwarmEvent;
-use mina_transport::{futures::StreamExt, Keypair};
+use mina_transport::{futures::StreamExt, keystore};
 use libp2p_rpc_behaviour::{Event, BehaviourBuilder};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let local_key = match File::open("target/identity") {
-        Ok(mut file) => {
-            let mut bytes = [0; 64];
-            file.read_exact(&mut bytes).unwrap();
-            Keypair::ed25519_from_bytes(bytes).unwrap()
-        }
-        Err(_) => {
-            let k = mina_transport::generate_identity();
-            let bytes = k.clone().into_ed25519().unwrap().encode();
-            File::create("target/identity")
-                .unwrap()
-                .write_all(&bytes)
-                .unwrap();
-            k
-        }
-    };
+    // the same format as `mina libp2p generate-keypair`, encrypted if `MINA_LIBP2P_PASS` is set
+    let local_key = keystore::load_or_generate(Some(Path::new("target/identity")))
+        .expect("failed to load the identity");
 
     let peers = [
         // "/ip4/135.181.217.23/tcp/30737/p2p/12D3KooWAVvZjW5m5LmhJrCUq2VtvG3drAsWxewMobgoUpewtqcp",
@@ -45,7 +29,8 @@ async fn main() {
     ];
     let behaviour = BehaviourBuilder::default().build();
 
-    let mut swarm = mina_transport::swarm(local_key, chain_id, listen_on, peers, behaviour);
+    let mut swarm = mina_transport::swarm(local_key, chain_id, listen_on, peers, behaviour)
+        .expect("failed to create the swarm");
     while let Some(event) = swarm.next().await {
         match event {
             SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
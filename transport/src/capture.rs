@@ -0,0 +1,256 @@
+//! Capture of the plaintext traffic: after pnet and noise are removed, before yamux.
+//!
+//! `FileCapture` writes the file in the following format. All integers are little endian.
+//! The file starts with the 8 bytes magic `MINACAP1`, then the records follow.
+//! Each record is one read from or one write to the socket:
+//!
+//! | size | field                                               |
+//! |------|-----------------------------------------------------|
+//! | 8    | timestamp, nanoseconds since unix epoch             |
+//! | 8    | connection id, unique within the process            |
+//! | 1    | direction, `0` written by us, `1` read from the peer |
+//! | 1    | length of the peer id                               |
+//! | n    | peer id, binary multihash                           |
+//! | 4    | length of the data                                  |
+//! | n    | data, yamux frames split at arbitrary positions     |
+//!
+//! Use `CaptureReader` to read the records and `YamuxDemux` to reassemble yamux frames.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use libp2p::PeerId;
+use tokio::sync::mpsc;
+
+use super::wire::{YamuxFrameType, YamuxHeader};
+
+const MAGIC: &[u8; 8] = b"MINACAP1";
+
+/// Receives every chunk of plaintext read from or written to a connection.
+pub trait CaptureSink: Send + Sync + 'static {
+    fn capture(&self, connection_id: u64, peer_id: &PeerId, incoming: bool, data: &[u8]);
+}
+
+/// Shared handle of the sink, to put it into `TransportConfig`.
+#[derive(Clone)]
+pub struct Capture(pub Arc<dyn CaptureSink>);
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Capture").finish()
+    }
+}
+
+impl Capture {
+    pub fn new<S>(sink: S) -> Self
+    where
+        S: CaptureSink,
+    {
+        Capture(Arc::new(sink))
+    }
+}
+
+pub(crate) fn next_connection_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Writes the capture into a file, see the module documentation for the format.
+/// The connections only encode the records and pass them to a writer thread,
+/// which flushes the file whenever it has written everything it was given.
+pub struct FileCapture {
+    records: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl FileCapture {
+    pub fn create<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.flush()?;
+        let (records, rx) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("capture".to_owned())
+            .spawn(move || {
+                if let Err(err) = write_records(file, rx) {
+                    log::error!("failed to write the capture: {err}");
+                }
+            })?;
+        Ok(FileCapture { records })
+    }
+}
+
+fn write_records(
+    mut file: BufWriter<File>,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(record) = rx.blocking_recv() {
+        file.write_all(&record)?;
+        while let Ok(record) = rx.try_recv() {
+            file.write_all(&record)?;
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
+
+impl CaptureSink for FileCapture {
+    fn capture(&self, connection_id: u64, peer_id: &PeerId, incoming: bool, data: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let peer_id = peer_id.to_bytes();
+
+        let mut record = Vec::with_capacity(22 + peer_id.len() + data.len());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&connection_id.to_le_bytes());
+        record.extend_from_slice(&[incoming as u8, peer_id.len() as u8]);
+        record.extend_from_slice(&peer_id);
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        // fails only if the writer thread stopped, it has logged the error
+        let _ = self.records.send(record);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub connection_id: u64,
+    pub peer_id: PeerId,
+    pub incoming: bool,
+    pub data: Vec<u8>,
+}
+
+/// Reads the file written by `FileCapture`.
+pub struct CaptureReader<R> {
+    inner: R,
+}
+
+impl<R> CaptureReader<R>
+where
+    R: Read,
+{
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a capture file",
+            ));
+        }
+        Ok(CaptureReader { inner })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0; 18];
+        match self.inner.read_exact(&mut head[..1]) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        self.inner.read_exact(&mut head[1..])?;
+        let timestamp = u64::from_le_bytes(head[..8].try_into().expect("cannot fail"));
+        let connection_id = u64::from_le_bytes(head[8..16].try_into().expect("cannot fail"));
+        let incoming = head[16] != 0;
+
+        let mut peer_id = vec![0; head[17] as usize];
+        self.inner.read_exact(&mut peer_id)?;
+        let peer_id = PeerId::from_bytes(&peer_id)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut len = [0; 4];
+        self.inner.read_exact(&mut len)?;
+        let mut data = vec![0; u32::from_le_bytes(len) as usize];
+        self.inner.read_exact(&mut data)?;
+
+        Ok(Some(Record {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_nanos(timestamp),
+            connection_id,
+            peer_id,
+            incoming,
+            data,
+        }))
+    }
+}
+
+impl<R> Iterator for CaptureReader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct YamuxFrame {
+    pub timestamp: SystemTime,
+    pub connection_id: u64,
+    pub peer_id: PeerId,
+    pub incoming: bool,
+    pub ty: YamuxFrameType,
+    pub flags: u16,
+    pub stream_id: u32,
+    /// For the data frame it is the length of the data,
+    /// otherwise window increment, ping opaque value or go away code.
+    pub length: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reassembles yamux frames from the chunks of each connection and direction.
+#[derive(Default)]
+pub struct YamuxDemux {
+    buffers: BTreeMap<(u64, bool), Vec<u8>>,
+}
+
+impl YamuxDemux {
+    /// Returns the frames completed by this record.
+    pub fn push(&mut self, record: Record) -> io::Result<Vec<YamuxFrame>> {
+        let buffer = self
+            .buffers
+            .entry((record.connection_id, record.incoming))
+            .or_default();
+        buffer.extend_from_slice(&record.data);
+
+        let mut frames = vec![];
+        while let Some(header) = buffer.get(..YamuxHeader::LEN) {
+            let header = YamuxHeader::parse(header.try_into().expect("cannot fail"))?;
+            let end = YamuxHeader::LEN + header.data_len();
+            if buffer.len() < end {
+                break;
+            }
+            let data = buffer[YamuxHeader::LEN..end].to_vec();
+            buffer.drain(..end);
+            frames.push(YamuxFrame {
+                timestamp: record.timestamp,
+                connection_id: record.connection_id,
+                peer_id: record.peer_id,
+                incoming: record.incoming,
+                ty: header.ty,
+                flags: header.flags,
+                stream_id: header.stream_id,
+                length: header.length,
+                data,
+            });
+        }
+
+        Ok(frames)
+    }
+}
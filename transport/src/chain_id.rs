@@ -0,0 +1,229 @@
+//! The chain id and the pnet key derived from it.
+//! Mina computes the chain id in `mina_cli_entrypoint.ml` when the daemon starts,
+//! this is the same computation.
+
+use blake2::{
+    digest::{generic_array::GenericArray, Update, VariableOutput},
+    Blake2bVar,
+};
+use md5::{Digest, Md5};
+
+/// The protocol constants that go into the chain id,
+/// from the `genesis` and `daemon` sections of the runtime config.
+#[derive(Clone, Debug)]
+pub struct GenesisConstants {
+    pub k: u32,
+    pub slots_per_epoch: u32,
+    pub slots_per_sub_window: u32,
+    pub delta: u32,
+    pub txpool_max_size: u32,
+    /// Milliseconds since unix epoch.
+    pub genesis_state_timestamp: u64,
+}
+
+/// The constants compiled into the daemon, the mainnet profile,
+/// they apply when the runtime config does not override them.
+impl Default for GenesisConstants {
+    fn default() -> Self {
+        GenesisConstants {
+            k: 290,
+            slots_per_epoch: 7140,
+            slots_per_sub_window: 7,
+            delta: 0,
+            txpool_max_size: 3000,
+            // 2020-09-16 03:15:00-07:00
+            genesis_state_timestamp: 1600251300000,
+        }
+    }
+}
+
+fn blake2b_256(data: &[u8]) -> [u8; 32] {
+    let mut out = GenericArray::default();
+    Blake2bVar::new(32)
+        .expect("valid constant")
+        .chain(data)
+        .finalize_variable(&mut out)
+        .expect("good buffer size");
+    out.into()
+}
+
+/// The key of the private network: blake2b-256 of `/coda/0.0.1/` followed by the chain id.
+pub fn pnet_key(chain_id: &[u8]) -> [u8; 32] {
+    blake2b_256(&[b"/coda/0.0.1/".as_slice(), chain_id].concat())
+}
+
+impl GenesisConstants {
+    /// `Genesis_constants.hash`: blake2b-256 hex of the decimal numbers
+    /// followed by the genesis timestamp as `Core.Time.to_string_abs` prints it in UTC.
+    pub fn hash(&self) -> String {
+        let s = format!(
+            "{}{}{}{}{}{}",
+            self.k,
+            self.slots_per_epoch,
+            self.slots_per_sub_window,
+            self.delta,
+            self.txpool_max_size,
+            format_timestamp(self.genesis_state_timestamp),
+        );
+        hex::encode(blake2b_256(s.as_bytes()))
+    }
+}
+
+/// The chain id as hex, the same string the Mina daemon logs and `swarm` takes.
+/// `constraint_system_digests` are the md5 hex digests of the circuits,
+/// the daemon prints them with `mina internal dump-constraint-system-digests`.
+/// `protocol_versions` is the transaction and network protocol version,
+/// the daemons that have them mix them into the chain id.
+pub fn chain_id(
+    genesis_state_hash: &str,
+    constraint_system_digests: &[String],
+    constants: &GenesisConstants,
+    protocol_versions: Option<(u32, u32)>,
+) -> String {
+    let mut s = genesis_state_hash.to_owned();
+    s.extend(constraint_system_digests.iter().map(String::as_str));
+    s.push_str(&constants.hash());
+    if let Some((transaction, network)) = protocol_versions {
+        for version in [transaction, network] {
+            s.push_str(&hex::encode(Md5::digest(version.to_string())));
+        }
+    }
+
+    hex::encode(blake2b_256(s.as_bytes()))
+}
+
+/// `YYYY-MM-DD HH:MM:SS.ffffffZ`
+fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}.{:06}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        ms % 1000 * 1000,
+    )
+}
+
+/// Parse the `genesis_state_timestamp` of the runtime config, e.g. `2023-02-23T20:00:01Z`
+/// or `2023-02-23 20:00:01.000000+00:00`, into milliseconds since unix epoch.
+pub fn parse_timestamp(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (date, rest) = s.split_at(s.find(['T', ' '])?);
+    let rest = &rest[1..];
+
+    let mut date = date.split('-');
+    let year = date.next()?.parse::<i64>().ok()?;
+    let month = date.next()?.parse::<u32>().ok()?;
+    let day = date.next()?.parse::<u32>().ok()?;
+
+    let (time, offset_secs) = match rest.find(['Z', '+', '-']) {
+        Some(pos) if &rest[pos..] == "Z" => (&rest[..pos], 0),
+        Some(pos) => {
+            let sign = if &rest[pos..(pos + 1)] == "-" { -1 } else { 1 };
+            let mut offset = rest[(pos + 1)..].split(':');
+            let hours = offset.next()?.parse::<i64>().ok()?;
+            let minutes = offset.next().unwrap_or("0").parse::<i64>().ok()?;
+            (&rest[..pos], sign * (hours * 3600 + minutes * 60))
+        }
+        None => (rest, 0),
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time = time.split(':');
+    let hours = time.next()?.parse::<i64>().ok()?;
+    let minutes = time.next()?.parse::<i64>().ok()?;
+    let seconds = time.next().unwrap_or("0").parse::<i64>().ok()?;
+    let ms = format!("{fraction:0<3}")[..3].parse::<i64>().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + seconds - offset_secs;
+    u64::try_from(secs * 1000 + ms).ok()
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chain_id, parse_timestamp, pnet_key, GenesisConstants};
+
+    // the expected values are computed independently with python `hashlib`
+    fn constants() -> GenesisConstants {
+        GenesisConstants {
+            k: 290,
+            slots_per_epoch: 7140,
+            slots_per_sub_window: 7,
+            delta: 0,
+            txpool_max_size: 3000,
+            genesis_state_timestamp: 1677182401000,
+        }
+    }
+
+    #[test]
+    fn timestamp() {
+        let ms = Some(1677182401000);
+        assert_eq!(parse_timestamp("2023-02-23T20:00:01Z"), ms);
+        assert_eq!(parse_timestamp("2023-02-23 20:00:01.000000+00:00"), ms);
+        assert_eq!(parse_timestamp("2023-02-23T22:00:01+02:00"), ms);
+
+        let compiled_in = GenesisConstants::default().genesis_state_timestamp;
+        assert_eq!(parse_timestamp("2020-09-16 03:15:00-07:00"), Some(compiled_in));
+    }
+
+    #[test]
+    fn genesis_constants_hash() {
+        assert_eq!(
+            constants().hash(),
+            "496a60dd582f8ddfb1ba9e219cc08976d64b1ecef68c2d1be750cfe0453ae1ed",
+        );
+    }
+
+    #[test]
+    fn chain_id_with_and_without_protocol_versions() {
+        let genesis_state_hash = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+        // md5 of `transaction` and `blockchain`, in place of the circuit digests
+        let digests = [
+            "f4d5b76a2418eba4baeabc1ed9142b54".to_owned(),
+            "5510a843bc1b7acb9507a5f71de51b98".to_owned(),
+        ];
+        assert_eq!(
+            chain_id(genesis_state_hash, &digests, &constants(), None),
+            "56c100a07c96b503f4f5d00b1a5b890d960ddc6d2dbd2573cdaddd89b46b7f17",
+        );
+        assert_eq!(
+            chain_id(genesis_state_hash, &digests, &constants(), Some((3, 0))),
+            "3b22b8757470a6fbe7eb732776421ef6570986ccfe9db2f0f4352f858b51b3f7",
+        );
+    }
+
+    #[test]
+    fn pnet_key_of_berkeley() {
+        let chain_id = b"667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db";
+        assert_eq!(
+            hex::encode(pnet_key(chain_id)),
+            "ff871436eb966dc242acc7fd53dffb480821c3f79c88f41f1a3267100b60569f",
+        );
+    }
+}
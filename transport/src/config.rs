@@ -0,0 +1,292 @@
+use std::{io, time::Duration};
+
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, MemoryTransport, OrTransport, TransportError as Libp2pTransportError},
+        upgrade,
+    },
+    dns,
+    futures::{AsyncRead, AsyncWrite},
+    noise, pnet, quic,
+    swarm::{DialError, NetworkBehaviour, SwarmBuilder},
+    tcp, webrtc, yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+use thiserror::Error;
+
+use super::{
+    capture::Capture, chain_id::pnet_key, idle::IdleMuxer, metrics::Metrics, muxer::CodaYamux,
+    Keypair,
+};
+
+/// Configuration of the transport and the swarm. The defaults match what `swarm` used to hardcode.
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    pub listen_on: Vec<Multiaddr>,
+    pub peers: Vec<Multiaddr>,
+    /// Use the in-process memory transport with `/memory/<port>` addresses instead of TCP.
+    /// The pnet, noise and yamux upgrades are the same.
+    pub memory: bool,
+    pub nodelay: bool,
+    /// Time limit for the pnet, noise and yamux upgrades of a new connection.
+    pub upgrade_timeout: Duration,
+    /// Resolve `/dns` addresses with the system resolver.
+    pub dns: bool,
+    pub yamux_receive_window: Option<u32>,
+    pub yamux_max_buffer_size: Option<usize>,
+    pub yamux_max_streams: Option<usize>,
+    /// Close the connection once no substream was open during this time,
+    /// the traffic of the open substreams does not count.
+    /// The swarm still closes it earlier once no behaviour keeps it alive.
+    pub idle_timeout: Option<Duration>,
+    /// Receives the plaintext of every connection, see `capture` module.
+    pub capture: Option<Capture>,
+    /// Counts bytes and streams per peer and protocol, see `metrics` module.
+    pub metrics: Option<Metrics>,
+    /// Also accept and dial `/udp/<port>/quic-v1` addresses. QUIC brings its own
+    /// TLS security and stream multiplexing, so there is no pnet, noise or yamux,
+    /// and the capture and metrics do not see these connections.
+    pub quic: bool,
+    /// Also accept and dial `/udp/<port>/webrtc` (WebRTC-direct) addresses,
+    /// secured by DTLS and noise over a data channel, without pnet and yamux.
+    pub webrtc: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("failed to configure dns: {0}")]
+    Dns(io::Error),
+    #[error("failed to create noise keys: {0}")]
+    Noise(#[from] noise::NoiseError),
+    #[error("failed to generate webrtc certificate: {0}")]
+    WebrtcCertificate(String),
+    #[error("failed to listen on {addr}: {err}")]
+    Listen {
+        addr: Multiaddr,
+        err: Libp2pTransportError<io::Error>,
+    },
+    #[error("failed to dial {addr}: {err}")]
+    Dial { addr: Multiaddr, err: DialError },
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            listen_on: vec![],
+            peers: vec![],
+            memory: false,
+            nodelay: true,
+            upgrade_timeout: Duration::from_secs(20),
+            dns: true,
+            yamux_receive_window: None,
+            yamux_max_buffer_size: None,
+            yamux_max_streams: None,
+            idle_timeout: None,
+            capture: None,
+            metrics: None,
+            quic: false,
+            webrtc: false,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn listen_on<J>(mut self, listen_on: J) -> Self
+    where
+        J: IntoIterator<Item = Multiaddr>,
+    {
+        self.listen_on.extend(listen_on);
+        self
+    }
+
+    pub fn peers<I>(mut self, peers: I) -> Self
+    where
+        I: IntoIterator<Item = Multiaddr>,
+    {
+        self.peers.extend(peers);
+        self
+    }
+
+    pub fn memory(mut self, memory: bool) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn upgrade_timeout(mut self, timeout: Duration) -> Self {
+        self.upgrade_timeout = timeout;
+        self
+    }
+
+    pub fn dns(mut self, dns: bool) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    pub fn yamux_receive_window(mut self, num_bytes: u32) -> Self {
+        self.yamux_receive_window = Some(num_bytes);
+        self
+    }
+
+    pub fn yamux_max_buffer_size(mut self, num_bytes: usize) -> Self {
+        self.yamux_max_buffer_size = Some(num_bytes);
+        self
+    }
+
+    pub fn yamux_max_streams(mut self, num_streams: usize) -> Self {
+        self.yamux_max_streams = Some(num_streams);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn quic(mut self, quic: bool) -> Self {
+        self.quic = quic;
+        self
+    }
+
+    pub fn webrtc(mut self, webrtc: bool) -> Self {
+        self.webrtc = webrtc;
+        self
+    }
+
+    fn yamux(&self) -> CodaYamux {
+        let mut inner = yamux::YamuxConfig::default();
+        if let Some(num_bytes) = self.yamux_receive_window {
+            inner.set_receive_window_size(num_bytes);
+        }
+        if let Some(num_bytes) = self.yamux_max_buffer_size {
+            inner.set_max_buffer_size(num_bytes);
+        }
+        if let Some(num_streams) = self.yamux_max_streams {
+            inner.set_max_num_streams(num_streams);
+        }
+
+        CodaYamux {
+            inner,
+            capture: self.capture.clone(),
+            metrics: self.metrics.clone(),
+            peer_id: None,
+        }
+    }
+
+    /// Secure the raw connections the way Mina does: pnet, then noise, then yamux.
+    fn upgrade<T>(
+        &self,
+        transport: T,
+        local_key: &Keypair,
+        chain_id: &[u8],
+    ) -> Result<Boxed<(PeerId, StreamMuxerBox)>, TransportError>
+    where
+        T: Transport + Send + Unpin + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Dial: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+    {
+        let pnet = pnet::PnetConfig::new(pnet::PreSharedKey::new(pnet_key(chain_id)));
+        let noise = noise::NoiseAuthenticated::xx(local_key)?;
+        let yamux = self.yamux();
+
+        Ok(transport
+            .and_then(move |socket, _| pnet.handshake(socket))
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex_ext(move |peer_id, _| yamux.clone().with_peer(*peer_id))
+            .timeout(self.upgrade_timeout)
+            .boxed())
+    }
+
+    /// Create the swarm, start listening and dial the peers.
+    /// A connection is closed once no behaviour keeps it alive or after the idle timeout,
+    /// put `ConnectionManager` into the behaviour to limit the number of connections.
+    pub fn build<B>(
+        self,
+        local_key: Keypair,
+        chain_id: &[u8],
+        behaviour: B,
+    ) -> Result<Swarm<B>, TransportError>
+    where
+        B: NetworkBehaviour,
+    {
+        let local_peer_id = PeerId::from(local_key.public());
+
+        let transport = if self.memory {
+            self.upgrade(MemoryTransport::default(), &local_key, chain_id)?
+        } else {
+            let tcp = tcp::tokio::Transport::new(tcp::Config::default().nodelay(self.nodelay));
+            let mut transport = self.upgrade(tcp, &local_key, chain_id)?;
+            // the address decides which one is used, TCP rejects `/udp` and vice versa
+            if self.quic {
+                let quic = quic::tokio::Transport::new(quic::Config::new(&local_key))
+                    .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+                    .boxed();
+                transport = or(transport, quic);
+            }
+            if self.webrtc {
+                let certificate = webrtc::tokio::Certificate::generate(&mut rand::thread_rng())
+                    .map_err(|err| TransportError::WebrtcCertificate(err.to_string()))?;
+                let webrtc = webrtc::tokio::Transport::new(local_key.clone(), certificate)
+                    .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+                    .boxed();
+                transport = or(transport, webrtc);
+            }
+            if self.dns {
+                dns::TokioDnsConfig::system(transport)
+                    .map_err(TransportError::Dns)?
+                    .boxed()
+            } else {
+                transport
+            }
+        };
+        let transport = match self.idle_timeout {
+            Some(timeout) => transport
+                .map(move |(peer_id, muxer), _| {
+                    (peer_id, StreamMuxerBox::new(IdleMuxer::new(muxer, timeout)))
+                })
+                .boxed(),
+            None => transport,
+        };
+        let mut swarm =
+            SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build();
+        for addr in self.listen_on {
+            swarm
+                .listen_on(addr.clone())
+                .map_err(|err| TransportError::Listen { addr, err })?;
+        }
+        for addr in self.peers {
+            swarm
+                .dial(addr.clone())
+                .map_err(|err| TransportError::Dial { addr, err })?;
+        }
+
+        Ok(swarm)
+    }
+}
+
+fn or(
+    a: Boxed<(PeerId, StreamMuxerBox)>,
+    b: Boxed<(PeerId, StreamMuxerBox)>,
+) -> Boxed<(PeerId, StreamMuxerBox)> {
+    OrTransport::new(a, b)
+        .map(|output, _| output.into_inner())
+        .map_err(|err| err.into_inner())
+        .boxed()
+}
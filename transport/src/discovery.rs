@@ -0,0 +1,146 @@
+use std::{borrow::Cow, collections::BTreeMap};
+
+use libp2p::{
+    identify,
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent, QueryResult},
+    multiaddr::Protocol,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    Multiaddr, PeerId,
+};
+
+use super::Keypair;
+
+/// The Kademlia protocol id of the Mina network.
+pub const KAD_PROTOCOL: &[u8] = b"/coda/kad/1.0.0";
+
+/// The identify protocol version the Mina libp2p helper announces.
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "ipfs/0.1.0";
+
+/// Peer discovery: Kademlia with the Mina protocol id and identify.
+/// Put it into the application behaviour next to gossipsub or RPC
+/// and pass its events to `on_event`, it keeps the routing table up to date.
+#[derive(NetworkBehaviour)]
+#[behaviour(prelude = "libp2p::swarm::derive_prelude")]
+pub struct Discovery {
+    pub kademlia: Toggle<Kademlia<MemoryStore>>,
+    pub identify: identify::Behaviour,
+}
+
+impl Discovery {
+    pub fn new(local_key: &Keypair) -> Self {
+        Self::with_kademlia(local_key, true)
+    }
+
+    /// Only identify, without Kademlia: the peers are not looked for,
+    /// but identify still reports the address the peers observe.
+    pub fn identify_only(local_key: &Keypair) -> Self {
+        Self::with_kademlia(local_key, false)
+    }
+
+    fn with_kademlia(local_key: &Keypair, enabled: bool) -> Self {
+        let local_peer_id = local_key.public().to_peer_id();
+
+        let kademlia = enabled.then(|| {
+            let mut config = KademliaConfig::default();
+            config.set_protocol_names(vec![Cow::Borrowed(KAD_PROTOCOL)]);
+            Kademlia::with_config(local_peer_id, MemoryStore::new(local_peer_id), config)
+        });
+
+        let config =
+            identify::Config::new(IDENTIFY_PROTOCOL_VERSION.to_owned(), local_key.public());
+        let identify = identify::Behaviour::new(config);
+
+        Discovery {
+            kademlia: kademlia.into(),
+            identify,
+        }
+    }
+
+    /// Add the seeds to the routing table. The address must end with `/p2p/<peer_id>`.
+    pub fn add_seeds<'a, I>(&mut self, seeds: I)
+    where
+        I: IntoIterator<Item = &'a Multiaddr>,
+    {
+        let Some(kademlia) = self.kademlia.as_mut() else {
+            return;
+        };
+        for addr in seeds {
+            match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => match PeerId::from_multihash(hash) {
+                    Ok(peer_id) => {
+                        kademlia.add_address(&peer_id, addr.clone());
+                    }
+                    Err(_) => log::warn!("bad peer id in the seed {addr}"),
+                },
+                _ => log::warn!("the seed {addr} has no peer id"),
+            }
+        }
+    }
+
+    /// Start a random walk to fill the routing table. Call it periodically.
+    pub fn bootstrap(&mut self) {
+        let Some(kademlia) = self.kademlia.as_mut() else {
+            return;
+        };
+        if let Err(err) = kademlia.bootstrap() {
+            log::warn!("cannot bootstrap kademlia: {err:?}");
+        }
+    }
+
+    /// The routing table: every known peer with its addresses.
+    pub fn peers(&mut self) -> BTreeMap<PeerId, Vec<Multiaddr>> {
+        let Some(kademlia) = self.kademlia.as_mut() else {
+            return BTreeMap::new();
+        };
+        kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| {
+                        let peer_id = *entry.node.key.preimage();
+                        let addrs = entry.node.value.iter().cloned().collect();
+                        (peer_id, addrs)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Update the routing table with the addresses the peer reports about itself.
+    /// Returns the peer if it was added to the routing table, the application may dial it.
+    pub fn on_event(&mut self, event: &DiscoveryEvent) -> Option<PeerId> {
+        match event {
+            DiscoveryEvent::Identify(identify::Event::Received { peer_id, info }) => {
+                let kad = String::from_utf8_lossy(KAD_PROTOCOL);
+                let Some(kademlia) = self.kademlia.as_mut() else {
+                    return None;
+                };
+                if info.protocols.iter().any(|p| *p == kad) {
+                    for addr in &info.listen_addrs {
+                        kademlia.add_address(peer_id, addr.clone());
+                    }
+                }
+                None
+            }
+            DiscoveryEvent::Kademlia(KademliaEvent::RoutingUpdated {
+                peer, is_new_peer, ..
+            }) => {
+                if *is_new_peer {
+                    log::debug!("discovered {peer}");
+                    Some(*peer)
+                } else {
+                    None
+                }
+            }
+            DiscoveryEvent::Kademlia(KademliaEvent::OutboundQueryProgressed {
+                result: QueryResult::Bootstrap(Err(err)),
+                ..
+            }) => {
+                log::warn!("kademlia bootstrap failed: {err:?}");
+                None
+            }
+            _ => None,
+        }
+    }
+}
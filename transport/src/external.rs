@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use libp2p::{
+    core::ConnectedPoint, identify, multiaddr::Protocol, swarm::SwarmEvent, Multiaddr, PeerId,
+};
+
+/// The address with `/p2p/<peer_id>` at the end, ready to paste into the config of a node.
+pub fn with_peer_id(addr: &Multiaddr, peer_id: PeerId) -> Multiaddr {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr.with(Protocol::P2p(peer_id.into()))
+}
+
+/// Collects the addresses under which the peers see us, as identify reports them.
+/// Only the inbound connections count: the port of an outbound connection
+/// is ephemeral, nobody can dial it. An address is confirmed when
+/// `min_observers` different peers report it.
+pub struct ExternalAddresses {
+    local_peer_id: PeerId,
+    min_observers: usize,
+    inbound: BTreeMap<PeerId, usize>,
+    observed: BTreeMap<Multiaddr, BTreeSet<PeerId>>,
+    confirmed: BTreeSet<Multiaddr>,
+}
+
+impl ExternalAddresses {
+    pub fn new(local_peer_id: PeerId, min_observers: usize) -> Self {
+        ExternalAddresses {
+            local_peer_id,
+            min_observers,
+            inbound: BTreeMap::new(),
+            observed: BTreeMap::new(),
+            confirmed: BTreeSet::new(),
+        }
+    }
+
+    pub fn on_swarm_event<TBehaviourOutEvent, THandlerErr>(
+        &mut self,
+        event: &SwarmEvent<TBehaviourOutEvent, THandlerErr>,
+    ) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint: ConnectedPoint::Listener { .. },
+                ..
+            } => {
+                *self.inbound.entry(*peer_id).or_default() += 1;
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint: ConnectedPoint::Listener { .. },
+                ..
+            } => {
+                if let Some(count) = self.inbound.get_mut(peer_id) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.inbound.remove(peer_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the address, with the peer id, if it just became confirmed.
+    pub fn on_identify(&mut self, event: &identify::Event) -> Option<Multiaddr> {
+        let identify::Event::Received { peer_id, info } = event else {
+            return None;
+        };
+        if !self.inbound.contains_key(peer_id) {
+            return None;
+        }
+        let mut addr = info.observed_addr.clone();
+        if let Some(Protocol::P2p(_)) = addr.iter().last() {
+            addr.pop();
+        }
+        let observers = self.observed.entry(addr.clone()).or_default();
+        observers.insert(*peer_id);
+        if observers.len() >= self.min_observers && self.confirmed.insert(addr.clone()) {
+            let addr = with_peer_id(&addr, self.local_peer_id);
+            log::info!("confirmed external address {addr}");
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    /// The confirmed addresses, with the peer id.
+    pub fn confirmed(&self) -> Vec<Multiaddr> {
+        self.confirmed
+            .iter()
+            .map(|addr| with_peer_id(addr, self.local_peer_id))
+            .collect()
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerEvent},
+    futures::{AsyncRead, AsyncWrite},
+};
+use tokio::time::{Instant, Sleep};
+
+/// The moment the last substream of the connection was closed.
+/// Every open substream holds a reference, so the connection is idle
+/// when the muxer holds the only one.
+struct Activity(Mutex<Instant>);
+
+pin_project_lite::pin_project! {
+    /// Closes the connection once no substream was open during the timeout.
+    /// A connection is idle only without substreams, the traffic of the open ones,
+    /// yamux pings included, does not matter, so a behaviour keeping a stream open
+    /// keeps the connection too. Works for every transport, QUIC and WebRTC included.
+    pub struct IdleMuxer<M> {
+        #[pin]
+        inner: M,
+        timeout: Duration,
+        activity: Arc<Activity>,
+        sleep: Pin<Box<Sleep>>,
+    }
+}
+
+impl<M> IdleMuxer<M> {
+    pub fn new(inner: M, timeout: Duration) -> Self {
+        IdleMuxer {
+            inner,
+            timeout,
+            activity: Arc::new(Activity(Mutex::new(Instant::now()))),
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+}
+
+impl<M> StreamMuxer for IdleMuxer<M>
+where
+    M: StreamMuxer,
+    M::Error: From<io::Error>,
+{
+    type Substream = IdleSubstream<M::Substream>;
+    type Error = M::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.project();
+        let inner = task::ready!(this.inner.poll_inbound(cx))?;
+        Poll::Ready(Ok(IdleSubstream::new(inner, this.activity)))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.project();
+        let inner = task::ready!(this.inner.poll_outbound(cx))?;
+        Poll::Ready(Ok(IdleSubstream::new(inner, this.activity)))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        let this = self.project();
+        // the sleep is only a hint to look again, the deadline follows the substreams
+        while this.sleep.as_mut().poll(cx).is_ready() {
+            let deadline = if Arc::strong_count(this.activity) > 1 {
+                Instant::now() + *this.timeout
+            } else {
+                *this.activity.0.lock().expect("poisoned") + *this.timeout
+            };
+            if deadline <= Instant::now() {
+                let err = io::Error::new(io::ErrorKind::TimedOut, "connection is idle");
+                return Poll::Ready(Err(err.into()));
+            }
+            this.sleep.as_mut().reset(deadline);
+        }
+        this.inner.poll(cx)
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// A substream keeping its connection from being idle until dropped.
+    pub struct IdleSubstream<S> {
+        #[pin]
+        inner: S,
+        activity: Arc<Activity>,
+    }
+
+    impl<S> PinnedDrop for IdleSubstream<S> {
+        fn drop(this: Pin<&mut Self>) {
+            *this.activity.0.lock().expect("poisoned") = Instant::now();
+        }
+    }
+}
+
+impl<S> IdleSubstream<S> {
+    fn new(inner: S, activity: &Arc<Activity>) -> Self {
+        IdleSubstream {
+            inner,
+            activity: activity.clone(),
+        }
+    }
+}
+
+impl<S> AsyncRead for IdleSubstream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for IdleSubstream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
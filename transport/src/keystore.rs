@@ -0,0 +1,332 @@
+//! Node identity in the format of the Mina libp2p keypair file,
+//! the one `mina libp2p generate-keypair -privkey-path <path>` writes.
+//!
+//! The keypair is the line `<private key>,<public key>,<peer id>`, where the keys are
+//! base64 of the libp2p protobuf encoding. Mina puts the line into a secret box:
+//! a JSON object with the xsalsa20poly1305 ciphertext and the argon2i parameters,
+//! the binary fields are base58check with version byte `0x02`.
+//! The password comes from `MINA_LIBP2P_PASS`, like in Mina.
+//! Without a password the line is stored as is.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use libp2p::identity::{DecodingError, PublicKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use xsalsa20poly1305::{aead::AeadInPlace, KeyInit, Nonce, Tag, XSalsa20Poly1305};
+
+use super::{ed25519, Keypair};
+
+pub const PASSWORD_ENV: &str = "MINA_LIBP2P_PASS";
+
+/// Base58 of the ed25519 secret key, the way the sandboxes used to take the identity.
+pub const SECRET_KEY_ENV: &str = "OPENMINA_P2P_SEC_KEY";
+
+const VERSION_BYTE: u8 = 0x02;
+const BOX_PRIMITIVE: &str = "xsalsa20poly1305";
+const PW_PRIMITIVE: &str = "argon2i";
+/// Memory in bytes and iterations, the moderate difficulty of libsodium Mina uses.
+const PW_DIFF: (u32, u32) = (134217728, 6);
+
+#[derive(Debug, Error)]
+pub enum KeystoreError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("bad secret box: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("bad base58: {0}")]
+    Base58(#[from] bs58::decode::Error),
+    #[error("bad base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("bad key: {0}")]
+    Key(#[from] DecodingError),
+    #[error("unsupported primitive {0}")]
+    UnsupportedPrimitive(String),
+    #[error("bad argon2 parameters: {0}")]
+    Argon2(argon2::Error),
+    #[error("wrong password or corrupted file")]
+    Decrypt,
+    #[error("bad keypair: {0}")]
+    BadFormat(&'static str),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SecretBox {
+    box_primitive: String,
+    pw_primitive: String,
+    nonce: String,
+    pwsalt: String,
+    pwdiff: (u32, u32),
+    ciphertext: String,
+}
+
+/// The line `<private key>,<public key>,<peer id>`.
+pub fn encode_keypair(keypair: &Keypair) -> String {
+    let sk = keypair
+        .to_protobuf_encoding()
+        .expect("ed25519 key can be encoded");
+    let pk = keypair.public().to_protobuf_encoding();
+    let peer_id = keypair.public().to_peer_id();
+    format!("{},{},{peer_id}", STANDARD.encode(sk), STANDARD.encode(pk))
+}
+
+pub fn decode_keypair(s: &str) -> Result<Keypair, KeystoreError> {
+    let mut parts = s.trim().split(',');
+    let sk = parts
+        .next()
+        .ok_or(KeystoreError::BadFormat("no private key"))?;
+    let keypair = Keypair::from_protobuf_encoding(&STANDARD.decode(sk)?)?;
+    if let Some(pk) = parts.next() {
+        let pk = PublicKey::from_protobuf_encoding(&STANDARD.decode(pk)?)?;
+        if pk != keypair.public() {
+            return Err(KeystoreError::BadFormat("public key does not match"));
+        }
+    }
+    if let Some(peer_id) = parts.next() {
+        if peer_id != keypair.public().to_peer_id().to_string() {
+            return Err(KeystoreError::BadFormat("peer id does not match"));
+        }
+    }
+    Ok(keypair)
+}
+
+fn derive_key(
+    password: &[u8],
+    salt: &[u8],
+    (mem, ops): (u32, u32),
+) -> Result<[u8; 32], KeystoreError> {
+    let params = Params::new(mem / 1024, ops, 1, Some(32)).map_err(KeystoreError::Argon2)?;
+    let mut key = [0; 32];
+    Argon2::new(Algorithm::Argon2i, Version::V0x13, params)
+        .hash_password_into(password, salt, &mut key)
+        .map_err(KeystoreError::Argon2)?;
+    Ok(key)
+}
+
+fn b58_encode(bytes: &[u8]) -> String {
+    bs58::encode(bytes)
+        .with_check_version(VERSION_BYTE)
+        .into_string()
+}
+
+fn b58_decode(s: &str) -> Result<Vec<u8>, KeystoreError> {
+    let mut bytes = bs58::decode(s).with_check(Some(VERSION_BYTE)).into_vec()?;
+    bytes.remove(0);
+    Ok(bytes)
+}
+
+fn seal(plaintext: &[u8], password: &[u8], pwdiff: (u32, u32)) -> Result<SecretBox, KeystoreError> {
+    let nonce = rand::random::<[u8; 24]>();
+    let salt = rand::random::<[u8; 16]>();
+    let key = derive_key(password, &salt, pwdiff)?;
+
+    let mut buffer = plaintext.to_vec();
+    let tag = XSalsa20Poly1305::new(&key.into())
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), b"", &mut buffer)
+        .map_err(|_| KeystoreError::Decrypt)?;
+    // libsodium puts the tag before the ciphertext
+    let mut ciphertext = tag.to_vec();
+    ciphertext.extend_from_slice(&buffer);
+
+    Ok(SecretBox {
+        box_primitive: BOX_PRIMITIVE.to_owned(),
+        pw_primitive: PW_PRIMITIVE.to_owned(),
+        nonce: b58_encode(&nonce),
+        pwsalt: b58_encode(&salt),
+        pwdiff,
+        ciphertext: b58_encode(&ciphertext),
+    })
+}
+
+fn open(secret_box: &SecretBox, password: &[u8]) -> Result<Vec<u8>, KeystoreError> {
+    if secret_box.box_primitive != BOX_PRIMITIVE {
+        return Err(KeystoreError::UnsupportedPrimitive(
+            secret_box.box_primitive.clone(),
+        ));
+    }
+    if secret_box.pw_primitive != PW_PRIMITIVE {
+        return Err(KeystoreError::UnsupportedPrimitive(
+            secret_box.pw_primitive.clone(),
+        ));
+    }
+    let nonce = b58_decode(&secret_box.nonce)?;
+    if nonce.len() != 24 {
+        return Err(KeystoreError::BadFormat("nonce must be 24 bytes"));
+    }
+    let salt = b58_decode(&secret_box.pwsalt)?;
+    let ciphertext = b58_decode(&secret_box.ciphertext)?;
+    if ciphertext.len() < 16 {
+        return Err(KeystoreError::Decrypt);
+    }
+    let key = derive_key(password, &salt, secret_box.pwdiff)?;
+
+    let (tag, ciphertext) = ciphertext.split_at(16);
+    let mut buffer = ciphertext.to_vec();
+    XSalsa20Poly1305::new(&key.into())
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            b"",
+            &mut buffer,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| KeystoreError::Decrypt)?;
+    Ok(buffer)
+}
+
+/// Write the keypair, encrypted if there is a password, and the peer id
+/// into `<path>.peerid` next to it, like Mina does.
+pub fn save(path: &Path, keypair: &Keypair, password: Option<&[u8]>) -> Result<(), KeystoreError> {
+    let line = encode_keypair(keypair);
+    let content = match password {
+        Some(password) => serde_json::to_string(&seal(line.as_bytes(), password, PW_DIFF)?)?,
+        None => line,
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    let mut peer_id_path = path.as_os_str().to_owned();
+    peer_id_path.push(".peerid");
+    fs::write(
+        PathBuf::from(peer_id_path),
+        keypair.public().to_peer_id().to_string(),
+    )?;
+
+    Ok(())
+}
+
+/// Read the keypair, the password is needed only if the file is a secret box,
+/// the empty password is tried if none is given.
+pub fn load(path: &Path, password: Option<&[u8]>) -> Result<Keypair, KeystoreError> {
+    let content = fs::read_to_string(path)?;
+    if content.trim_start().starts_with('{') {
+        let secret_box = serde_json::from_str::<SecretBox>(&content)?;
+        let plaintext = open(&secret_box, password.unwrap_or_default())?;
+        let line =
+            String::from_utf8(plaintext).map_err(|_| KeystoreError::BadFormat("not utf-8"))?;
+        decode_keypair(&line)
+    } else {
+        decode_keypair(&content)
+    }
+}
+
+pub fn password_from_env() -> Option<Vec<u8>> {
+    env::var(PASSWORD_ENV).ok().map(String::into_bytes)
+}
+
+/// Generate a new keypair and save it, encrypted with the password from `MINA_LIBP2P_PASS`.
+/// Without the password the key is saved as is, with a warning.
+pub fn generate(path: &Path) -> Result<Keypair, KeystoreError> {
+    let keypair = super::generate_identity();
+    let password = password_from_env();
+    if password.is_none() {
+        let path = path.display();
+        log::warn!("{PASSWORD_ENV} is not set, the key in {path} is not encrypted");
+    }
+    save(path, &keypair, password.as_deref())?;
+
+    Ok(keypair)
+}
+
+/// The identity of a binary. With a path, load the keypair from it,
+/// or generate a new one and save it there, so the peer id is stable across restarts.
+/// Without a path, take the secret key from `OPENMINA_P2P_SEC_KEY` or generate a random one.
+pub fn load_or_generate(path: Option<&Path>) -> Result<Keypair, KeystoreError> {
+    let password = password_from_env();
+    let keypair = match path {
+        Some(path) if path.exists() => load(path, password.as_deref())?,
+        Some(path) => {
+            let keypair = generate(path)?;
+            log::info!("saved new identity to {}", path.display());
+            keypair
+        }
+        None => match env::var(SECRET_KEY_ENV) {
+            Ok(key) => {
+                let mut bytes = bs58::decode(key).with_check(Some(0x80)).into_vec()?;
+                let sk = ed25519::SecretKey::from_bytes(&mut bytes[1..])?;
+                ed25519::Keypair::from(sk).into()
+            }
+            Err(_) => super::generate_identity(),
+        },
+    };
+    log::info!("{}", keypair.public().to_peer_id());
+
+    Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use super::{encode_keypair, load, open, save, seal, KeystoreError};
+
+    // the cheapest argon2i parameters, the ones of Mina take seconds
+    const PW_DIFF: (u32, u32) = (8192, 1);
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("keystore-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn seal_open() {
+        let line = encode_keypair(&crate::generate_identity());
+        let secret_box = seal(line.as_bytes(), b"password", PW_DIFF).unwrap();
+        let plaintext = open(&secret_box, b"password").unwrap();
+        assert_eq!(plaintext, line.as_bytes());
+    }
+
+    #[test]
+    fn seal_open_wrong_password() {
+        let secret_box = seal(b"keypair", b"password", PW_DIFF).unwrap();
+        assert!(matches!(
+            open(&secret_box, b"wrong"),
+            Err(KeystoreError::Decrypt)
+        ));
+        assert!(matches!(
+            open(&secret_box, b""),
+            Err(KeystoreError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn save_load_without_password() {
+        let path = temp_path("plain");
+        let keypair = crate::generate_identity();
+        save(&path, &keypair, None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), encode_keypair(&keypair));
+        let loaded = load(&path, None).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+        // the password is ignored for a plain keypair
+        let loaded = load(&path, Some(b"password")).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+        let peer_id = fs::read_to_string(temp_path("plain.peerid")).unwrap();
+        assert_eq!(peer_id, keypair.public().to_peer_id().to_string());
+    }
+
+    #[test]
+    fn load_wrong_password() {
+        let path = temp_path("sealed");
+        let keypair = crate::generate_identity();
+        let line = encode_keypair(&keypair);
+        let secret_box = seal(line.as_bytes(), b"password", PW_DIFF).unwrap();
+        fs::write(&path, serde_json::to_string(&secret_box).unwrap()).unwrap();
+        let loaded = load(&path, Some(b"password")).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+        assert!(matches!(
+            load(&path, Some(b"wrong")),
+            Err(KeystoreError::Decrypt)
+        ));
+        assert!(matches!(load(&path, None), Err(KeystoreError::Decrypt)));
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use libp2p::{
+    connection_limits::{self, ConnectionLimits},
+    core::Endpoint,
+    multiaddr::Protocol,
+    swarm::{
+        dial_opts::DialOpts, dummy, CloseConnection, ConnectionDenied, ConnectionId, FromSwarm,
+        NetworkBehaviour, PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use thiserror::Error;
+use tokio::time::{Instant, Sleep};
+
+/// Connection management: limits the number of inbound and outbound connections,
+/// redials the configured peers with exponential backoff after they disconnect
+/// or the dial fails, and refuses the peers the application banned.
+/// Put it into the application behaviour next to gossipsub or RPC.
+/// The limits are enforced by the `connection_limits` behaviour of libp2p.
+pub struct ConnectionManager {
+    config: ConnectionManagerConfig,
+    banned: BTreeSet<PeerId>,
+    limits: connection_limits::Behaviour,
+    redial: BTreeMap<PeerId, Redial>,
+    timer: Pin<Box<Sleep>>,
+    actions: VecDeque<ToSwarm<Infallible, THandlerInEvent<Self>>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionManagerConfig {
+    pub max_inbound: Option<u32>,
+    pub max_outbound: Option<u32>,
+    pub max_per_peer: Option<u32>,
+    /// Inbound connections still in the handshake, so a burst of them
+    /// is refused before the upgrades run rather than after.
+    pub max_pending_inbound: Option<u32>,
+    /// The first redial happens after this delay, each failure doubles it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Keep these peers connected. The address must end with `/p2p/<peer_id>`.
+    pub peers: Vec<Multiaddr>,
+}
+
+impl Default for ConnectionManagerConfig {
+    fn default() -> Self {
+        ConnectionManagerConfig {
+            max_inbound: None,
+            max_outbound: None,
+            max_per_peer: None,
+            max_pending_inbound: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            peers: vec![],
+        }
+    }
+}
+
+struct Redial {
+    addr: Multiaddr,
+    backoff: Duration,
+    /// `None` while connected or dialing.
+    at: Option<Instant>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectionRefused {
+    #[error("the peer {0} is banned")]
+    Banned(PeerId),
+}
+
+impl ConnectionManager {
+    pub fn new(config: ConnectionManagerConfig) -> Self {
+        let mut redial = BTreeMap::new();
+        for addr in &config.peers {
+            let peer_id = match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+                _ => None,
+            };
+            let Some(peer_id) = peer_id else {
+                log::warn!("cannot redial {addr}, it has no peer id");
+                continue;
+            };
+            let entry = Redial {
+                addr: addr.clone(),
+                backoff: config.initial_backoff,
+                at: None,
+            };
+            redial.insert(peer_id, entry);
+        }
+        let limits = ConnectionLimits::default()
+            .with_max_established_incoming(config.max_inbound)
+            .with_max_established_outgoing(config.max_outbound)
+            .with_max_established_per_peer(config.max_per_peer)
+            .with_max_pending_incoming(config.max_pending_inbound);
+
+        ConnectionManager {
+            config,
+            banned: BTreeSet::new(),
+            limits: connection_limits::Behaviour::new(limits),
+            redial,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            actions: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    /// Close all connections to the peer and refuse new ones, e.g. after it sent garbage.
+    pub fn ban(&mut self, peer_id: PeerId) {
+        if self.banned.insert(peer_id) {
+            log::info!("ban {peer_id}");
+            self.actions.push_back(ToSwarm::CloseConnection {
+                peer_id,
+                connection: CloseConnection::All,
+            });
+            self.wake();
+        }
+    }
+
+    pub fn unban(&mut self, peer_id: &PeerId) {
+        if self.banned.remove(peer_id) {
+            log::info!("unban {peer_id}");
+            if let Some(redial) = self.redial.get_mut(peer_id) {
+                redial.at = Some(Instant::now());
+                self.wake();
+            }
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned.contains(peer_id)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &PeerId> {
+        self.banned.iter()
+    }
+
+    fn check_banned(&self, peer_id: &PeerId) -> Result<(), ConnectionDenied> {
+        if self.banned.contains(peer_id) {
+            return Err(ConnectionDenied::new(ConnectionRefused::Banned(*peer_id)));
+        }
+        Ok(())
+    }
+
+    fn schedule_redial(&mut self, peer_id: &PeerId) {
+        if self.banned.contains(peer_id) {
+            return;
+        }
+        let max_backoff = self.config.max_backoff;
+        if let Some(redial) = self.redial.get_mut(peer_id) {
+            log::debug!("redial {peer_id} in {:?}", redial.backoff);
+            redial.at = Some(Instant::now() + redial.backoff);
+            redial.backoff = (redial.backoff * 2).min(max_backoff);
+        }
+    }
+}
+
+impl NetworkBehaviour for ConnectionManager {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.limits
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check_banned(&peer)?;
+        self.limits.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if let Some(peer_id) = maybe_peer {
+            self.check_banned(&peer_id)?;
+        }
+        self.limits.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check_banned(&peer)?;
+        self.limits.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match &event {
+            FromSwarm::ConnectionEstablished(event) => {
+                if let Some(redial) = self.redial.get_mut(&event.peer_id) {
+                    redial.backoff = self.config.initial_backoff;
+                    redial.at = None;
+                }
+            }
+            FromSwarm::ConnectionClosed(event) => {
+                if event.remaining_established == 0 {
+                    self.schedule_redial(&event.peer_id);
+                }
+            }
+            FromSwarm::DialFailure(event) => {
+                if let Some(peer_id) = event.peer_id {
+                    self.schedule_redial(&peer_id);
+                }
+            }
+            _ => {}
+        }
+        self.limits.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        // `self.limits` only refuses connections, it has nothing to poll
+        if let Some(action) = self.actions.pop_front() {
+            return Poll::Ready(action);
+        }
+        self.waker = Some(cx.waker().clone());
+
+        let now = Instant::now();
+        let due = self
+            .redial
+            .iter_mut()
+            .find(|(_, redial)| redial.at.map_or(false, |at| at <= now));
+        if let Some((peer_id, redial)) = due {
+            redial.at = None;
+            let opts = DialOpts::peer_id(*peer_id)
+                .addresses(vec![redial.addr.clone()])
+                .build();
+            return Poll::Ready(ToSwarm::Dial { opts });
+        }
+
+        if let Some(next) = self.redial.values().filter_map(|redial| redial.at).min() {
+            self.timer.as_mut().reset(next);
+            if self.timer.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+
+        Poll::Pending
+    }
+}
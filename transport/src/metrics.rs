@@ -0,0 +1,418 @@
+//! Bytes and streams per peer and per protocol, counted on the plaintext yamux traffic.
+//!
+//! The `SocketWrapper` follows the yamux frames of each connection and the
+//! multistream-select negotiation of each stream, so the data of a stream
+//! is attributed to the protocol it negotiated, e.g. `coda/rpcs/0.0.1` or `/meshsub/1.1.0`.
+//! A protocol counts once both sides agreed on it, unknown protocols count as `other`.
+//! Yamux headers and data sent before the protocol is known count only for the peer.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use libp2p::PeerId;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use super::wire::{self, yamux_flags, YamuxFrameType, YamuxHeader};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub streams_opened: u64,
+    pub streams_open: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    pub peers: BTreeMap<PeerId, Traffic>,
+    pub protocols: BTreeMap<String, Traffic>,
+}
+
+/// Shared handle, put it into `TransportConfig` and read it from anywhere.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsSnapshot>>);
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Metrics").finish()
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.0.lock().expect("poisoned").clone()
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MetricsSnapshot),
+    {
+        f(&mut self.0.lock().expect("poisoned"))
+    }
+
+    /// The Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut s = String::new();
+        let metrics = [
+            ("bytes_in_total", "counter", "Bytes received."),
+            ("bytes_out_total", "counter", "Bytes sent."),
+            ("streams_opened_total", "counter", "Yamux streams opened."),
+            ("streams_open", "gauge", "Yamux streams currently open."),
+        ];
+        for (i, (name, ty, help)) in metrics.into_iter().enumerate() {
+            let value =
+                |t: &Traffic| [t.bytes_in, t.bytes_out, t.streams_opened, t.streams_open][i];
+
+            let name_peer = format!("mina_transport_peer_{name}");
+            writeln!(s, "# HELP {name_peer} {help}").unwrap();
+            writeln!(s, "# TYPE {name_peer} {ty}").unwrap();
+            for (peer_id, t) in &snapshot.peers {
+                let peer_id = escape_label(&peer_id.to_string());
+                writeln!(s, "{name_peer}{{peer=\"{peer_id}\"}} {}", value(t)).unwrap();
+            }
+
+            let name_protocol = format!("mina_transport_protocol_{name}");
+            writeln!(s, "# HELP {name_protocol} {help}").unwrap();
+            writeln!(s, "# TYPE {name_protocol} {ty}").unwrap();
+            for (protocol, t) in &snapshot.protocols {
+                let protocol = escape_label(protocol);
+                writeln!(s, "{name_protocol}{{protocol=\"{protocol}\"}} {}", value(t)).unwrap();
+            }
+        }
+        s
+    }
+
+    /// Answer every HTTP request on the address with the Prometheus text. Runs forever.
+    pub async fn serve_prometheus(self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("metrics on http://{addr}/metrics");
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let body = self.prometheus();
+            tokio::spawn(async move {
+                // the request is not interesting, any path gets the metrics
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{body}",
+                    body.len(),
+                );
+                if let Err(err) = stream.write_all(response.as_bytes()).await {
+                    log::debug!("metrics: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Stop looking for the protocol name if the stream starts with something else.
+const MAX_NEGOTIATION_LEN: usize = 1024;
+
+/// Follows the yamux frames of one connection.
+pub(crate) struct ConnectionMetrics {
+    metrics: Metrics,
+    peer_id: PeerId,
+    incoming: FrameParser,
+    outgoing: FrameParser,
+    streams: BTreeMap<u32, StreamState>,
+}
+
+#[derive(Default)]
+struct FrameParser {
+    header: Vec<u8>,
+    stream_id: u32,
+    remaining: usize,
+    /// Not yamux, only the bytes of the peer are counted.
+    broken: bool,
+}
+
+#[derive(Default)]
+struct StreamState {
+    protocol: Option<String>,
+    /// The remote peer opened the stream, it is the multistream-select dialer.
+    opened_by_remote: bool,
+    /// The beginning of the stream in each direction, until the protocol is known.
+    negotiation: [Vec<u8>; 2],
+    fin: [bool; 2],
+}
+
+impl StreamState {
+    /// Look for the protocol in the multistream-select messages. The protocol is known
+    /// once the listener accepts the proposal of the dialer by echoing it, the proposals
+    /// it refused with `na` are not counted. Returns the length of the application data
+    /// that came together with the negotiation in each direction.
+    fn negotiate(&mut self, incoming: bool, data: &[u8]) -> Option<[u64; 2]> {
+        let buffer = &mut self.negotiation[incoming as usize];
+        if buffer.len() >= MAX_NEGOTIATION_LEN {
+            return None;
+        }
+        buffer.extend_from_slice(data);
+
+        let dialer = &self.negotiation[self.opened_by_remote as usize];
+        let listener = &self.negotiation[!self.opened_by_remote as usize];
+        let mut proposals = vec![];
+        let mut pos = 0;
+        while let Some((line, len)) = wire::multistream_message(&dialer[pos..]) {
+            pos += len;
+            if wire::is_protocol(&line) {
+                proposals.push((line, pos));
+            }
+        }
+        let mut refused = 0;
+        let mut pos = 0;
+        let (protocol, dialer_end, listener_end) = loop {
+            let (line, len) = wire::multistream_message(&listener[pos..])?;
+            pos += len;
+            if line == "na" {
+                refused += 1;
+            } else if wire::is_protocol(&line) {
+                let (proposal, end) = proposals.get(refused)?;
+                if *proposal != line {
+                    return None;
+                }
+                break (line, *end, pos);
+            }
+        };
+
+        let mut rest = [0; 2];
+        rest[self.opened_by_remote as usize] = (dialer.len() - dialer_end) as u64;
+        rest[!self.opened_by_remote as usize] = (listener.len() - listener_end) as u64;
+        self.protocol = Some(protocol_label(&protocol).to_owned());
+        self.negotiation = Default::default();
+        Some(rest)
+    }
+}
+
+/// The protocols Mina nodes speak. Anything else the peers agree on is counted
+/// as `other`, so a peer cannot create an unbounded number of label values.
+const KNOWN_PROTOCOLS: &[&str] = &[
+    "coda/rpcs/0.0.1",
+    "/meshsub/1.1.0",
+    "/meshsub/1.0.0",
+    "/floodsub/1.0.0",
+    "/coda/kad/1.0.0",
+    "/ipfs/id/1.0.0",
+    "/ipfs/id/push/1.0.0",
+    "/ipfs/ping/1.0.0",
+    "/mina/peer-exchange",
+    "/mina/node-status",
+    "/mina/bitswap-exchange",
+];
+
+fn protocol_label(protocol: &str) -> &str {
+    KNOWN_PROTOCOLS
+        .iter()
+        .find(|known| **known == protocol)
+        .unwrap_or(&"other")
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl ConnectionMetrics {
+    pub fn new(metrics: Metrics, peer_id: PeerId) -> Self {
+        ConnectionMetrics {
+            metrics,
+            peer_id,
+            incoming: FrameParser::default(),
+            outgoing: FrameParser::default(),
+            streams: BTreeMap::new(),
+        }
+    }
+
+    pub fn on_data(&mut self, incoming: bool, mut data: &[u8]) {
+        let peer_id = self.peer_id;
+        self.metrics.update(|m| {
+            let peer = m.peers.entry(peer_id).or_default();
+            if incoming {
+                peer.bytes_in += data.len() as u64;
+            } else {
+                peer.bytes_out += data.len() as u64;
+            }
+        });
+
+        while !data.is_empty() {
+            let parser = if incoming {
+                &mut self.incoming
+            } else {
+                &mut self.outgoing
+            };
+            if parser.broken {
+                break;
+            }
+            if parser.remaining > 0 {
+                let len = parser.remaining.min(data.len());
+                parser.remaining -= len;
+                let stream_id = parser.stream_id;
+                self.on_stream_data(incoming, stream_id, &data[..len]);
+                data = &data[len..];
+                continue;
+            }
+
+            let len = (YamuxHeader::LEN - parser.header.len()).min(data.len());
+            parser.header.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if parser.header.len() < YamuxHeader::LEN {
+                break;
+            }
+            let header = std::mem::take(&mut parser.header);
+            let header = match YamuxHeader::parse(header[..].try_into().expect("cannot fail")) {
+                Ok(header) => header,
+                Err(err) => {
+                    log::debug!("metrics: stop following {peer_id}: {err}");
+                    parser.broken = true;
+                    break;
+                }
+            };
+            if header.ty == YamuxFrameType::Data {
+                parser.stream_id = header.stream_id;
+                parser.remaining = header.data_len();
+            }
+            // ping and go away use stream 0
+            if header.stream_id != 0 {
+                self.on_flags(incoming, header.stream_id, header.flags);
+            }
+        }
+    }
+
+    fn on_flags(&mut self, incoming: bool, stream_id: u32, flags: u16) {
+        let peer_id = self.peer_id;
+        if flags & yamux_flags::SYN != 0 && !self.streams.contains_key(&stream_id) {
+            let stream = StreamState {
+                opened_by_remote: incoming,
+                ..Default::default()
+            };
+            self.streams.insert(stream_id, stream);
+            self.metrics.update(|m| {
+                let peer = m.peers.entry(peer_id).or_default();
+                peer.streams_opened += 1;
+                peer.streams_open += 1;
+            });
+        }
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        if flags & yamux_flags::FIN != 0 {
+            stream.fin[incoming as usize] = true;
+        }
+        if flags & yamux_flags::RST != 0 || stream.fin == [true, true] {
+            let stream = self.streams.remove(&stream_id).expect("checked above");
+            self.metrics
+                .update(|m| close_stream(m, &peer_id, stream.protocol.as_deref()));
+        }
+    }
+
+    fn on_stream_data(&mut self, incoming: bool, stream_id: u32, data: &[u8]) {
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let rest = match &stream.protocol {
+            Some(_) => None,
+            None => match stream.negotiate(incoming, data) {
+                Some(rest) => Some(rest),
+                None => return,
+            },
+        };
+        let protocol = stream.protocol.clone().expect("checked above");
+        self.metrics.update(|m| {
+            let t = m.protocols.entry(protocol).or_default();
+            match rest {
+                Some([bytes_out, bytes_in]) => {
+                    t.streams_opened += 1;
+                    t.streams_open += 1;
+                    t.bytes_in += bytes_in;
+                    t.bytes_out += bytes_out;
+                }
+                None if incoming => t.bytes_in += data.len() as u64,
+                None => t.bytes_out += data.len() as u64,
+            }
+        });
+    }
+}
+
+fn close_stream(m: &mut MetricsSnapshot, peer_id: &PeerId, protocol: Option<&str>) {
+    if let Some(peer) = m.peers.get_mut(peer_id) {
+        peer.streams_open = peer.streams_open.saturating_sub(1);
+    }
+    if let Some(t) = protocol.and_then(|protocol| m.protocols.get_mut(protocol)) {
+        t.streams_open = t.streams_open.saturating_sub(1);
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        let streams = std::mem::take(&mut self.streams);
+        let peer_id = self.peer_id;
+        self.metrics.update(|m| {
+            for stream in streams.values() {
+                close_stream(m, &peer_id, stream.protocol.as_deref());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_label, StreamState};
+
+    fn message(line: &str) -> Vec<u8> {
+        let mut bytes = vec![line.len() as u8 + 1];
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        bytes
+    }
+
+    #[test]
+    fn negotiate_after_refusal() {
+        let mut stream = StreamState::default();
+        let mut dialer = [message("/multistream/1.0.0"), message("/unknown/1.0.0")].concat();
+        assert_eq!(stream.negotiate(false, &dialer), None);
+        let listener = [message("/multistream/1.0.0"), message("na")].concat();
+        assert_eq!(stream.negotiate(true, &listener), None);
+        assert_eq!(stream.protocol, None);
+
+        dialer = [message("coda/rpcs/0.0.1"), b"data".to_vec()].concat();
+        assert_eq!(stream.negotiate(false, &dialer), None);
+        assert_eq!(
+            stream.negotiate(true, &message("coda/rpcs/0.0.1")),
+            Some([4, 0])
+        );
+        assert_eq!(stream.protocol.as_deref(), Some("coda/rpcs/0.0.1"));
+    }
+
+    #[test]
+    fn negotiate_unknown() {
+        let mut stream = StreamState {
+            opened_by_remote: true,
+            ..Default::default()
+        };
+        assert_eq!(stream.negotiate(true, &message("/spam/\"1\"")), None);
+        assert_eq!(
+            stream.negotiate(false, &message("/spam/\"1\"")),
+            Some([0, 0])
+        );
+        assert_eq!(stream.protocol.as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
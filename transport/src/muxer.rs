@@ -0,0 +1,171 @@
+use std::{
+    pin::Pin,
+    task::{self, Context, Poll},
+    io,
+};
+
+use libp2p::{
+    core::{UpgradeInfo, InboundUpgrade, OutboundUpgrade},
+    futures::{AsyncRead, AsyncWrite},
+    yamux, PeerId,
+};
+
+use super::{
+    capture::{self, Capture},
+    metrics::{ConnectionMetrics, Metrics},
+};
+
+/// The yamux upgrade under the protocol name Mina uses.
+#[derive(Clone)]
+pub struct CodaYamux {
+    pub inner: yamux::YamuxConfig,
+    pub capture: Option<Capture>,
+    pub metrics: Option<Metrics>,
+    /// The remote peer, known after noise, set by `with_peer`.
+    pub peer_id: Option<PeerId>,
+}
+
+pin_project_lite::pin_project! {
+    /// The socket after pnet and noise, it carries plaintext yamux frames.
+    pub struct SocketWrapper<C> {
+        #[pin]
+        inner: C,
+        capture: Option<ConnectionCapture>,
+        metrics: Option<ConnectionMetrics>,
+    }
+}
+
+/// The sink and the identity of the connection the chunks belong to.
+pub struct ConnectionCapture {
+    sink: Capture,
+    connection_id: u64,
+    peer_id: PeerId,
+}
+
+impl ConnectionCapture {
+    fn capture(&self, incoming: bool, data: &[u8]) {
+        self.sink
+            .0
+            .capture(self.connection_id, &self.peer_id, incoming, data);
+    }
+}
+
+impl<C> AsyncWrite for SocketWrapper<C>
+where
+    C: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let len = task::ready!(this.inner.poll_write(cx, buf))?;
+        if len != 0 {
+            if let Some(capture) = this.capture {
+                capture.capture(false, &buf[..len]);
+            }
+            if let Some(metrics) = this.metrics {
+                metrics.on_data(false, &buf[..len]);
+            }
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.inner.poll_close(cx)
+    }
+}
+
+impl<C> AsyncRead for SocketWrapper<C>
+where
+    C: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let len = task::ready!(this.inner.poll_read(cx, buf))?;
+        if len != 0 {
+            if let Some(capture) = this.capture {
+                capture.capture(true, &buf[..len]);
+            }
+            if let Some(metrics) = this.metrics {
+                metrics.on_data(true, &buf[..len]);
+            }
+        }
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl CodaYamux {
+    pub fn with_peer(mut self, peer_id: PeerId) -> Self {
+        self.peer_id = Some(peer_id);
+        self
+    }
+
+    fn wrap<C>(&self, socket: C) -> SocketWrapper<C> {
+        let peer_id = self.peer_id.unwrap_or_else(PeerId::random);
+        let capture = self.capture.clone().map(|sink| ConnectionCapture {
+            sink,
+            connection_id: capture::next_connection_id(),
+            peer_id,
+        });
+        let metrics = self
+            .metrics
+            .clone()
+            .map(|metrics| ConnectionMetrics::new(metrics, peer_id));
+        SocketWrapper {
+            inner: socket,
+            capture,
+            metrics,
+        }
+    }
+}
+
+impl UpgradeInfo for CodaYamux {
+    type Info = &'static [u8];
+    type InfoIter = std::iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        std::iter::once(b"/coda/yamux/1.0.0")
+    }
+}
+
+impl<C> InboundUpgrade<C> for CodaYamux
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<C>>>::Output;
+    type Error = <yamux::YamuxConfig as InboundUpgrade<C>>::Error;
+    type Future = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<C>>>::Future;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = self.wrap(socket);
+        self.inner.upgrade_inbound(socket, info)
+    }
+}
+
+impl<C> OutboundUpgrade<C> for CodaYamux
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Output = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<C>>>::Output;
+    type Error = <yamux::YamuxConfig as OutboundUpgrade<C>>::Error;
+    type Future = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<C>>>::Future;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = self.wrap(socket);
+        self.inner.upgrade_outbound(socket, info)
+    }
+}
@@ -0,0 +1,46 @@
+//! The error response for the RPC methods a responder does not serve,
+//! shared by the sandboxes answering Mina peers.
+
+use std::io;
+
+use binprot::{BinProtRead, BinProtWrite};
+use mina_p2p_messages::rpc_kernel::{self, QueryHeader, RpcMethod};
+
+/// Nothing on the wire, the query and the response of `Unimplemented`.
+#[derive(Debug, Clone)]
+pub struct Nothing;
+
+impl BinProtWrite for Nothing {
+    fn binprot_write<W: io::Write>(&self, _w: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BinProtRead for Nothing {
+    fn binprot_read<R: io::Read + ?Sized>(_r: &mut R) -> Result<Self, binprot::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Nothing)
+    }
+}
+
+/// A method the responder does not know, only to send an error on its behalf.
+/// The response header carries only the query id, so the error is the same
+/// whatever the method is. Respond with `Err(unimplemented(header))`.
+pub struct Unimplemented;
+
+impl RpcMethod for Unimplemented {
+    const NAME: &'static str = "";
+    const VERSION: i32 = 0;
+    type Query = Nothing;
+    type Response = Nothing;
+}
+
+/// The error telling the peer the method of the query is not served.
+pub fn unimplemented(header: &QueryHeader) -> rpc_kernel::Error {
+    rpc_kernel::Error::UnimplementedRpc(
+        header.tag.clone(),
+        rpc_kernel::Version::Version(header.version),
+    )
+}
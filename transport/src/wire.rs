@@ -0,0 +1,99 @@
+//! Parsers of the plaintext traffic, shared by `capture`, `metrics` and the tools:
+//! the yamux frame header and the multistream-select messages at the start of a stream.
+
+use std::io;
+
+pub mod yamux_flags {
+    pub const SYN: u16 = 1;
+    pub const ACK: u16 = 2;
+    pub const FIN: u16 = 4;
+    pub const RST: u16 = 8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YamuxFrameType {
+    Data,
+    WindowUpdate,
+    Ping,
+    GoAway,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct YamuxHeader {
+    pub ty: YamuxFrameType,
+    pub flags: u16,
+    pub stream_id: u32,
+    /// For the data frame it is the length of the data,
+    /// otherwise window increment, ping opaque value or go away code.
+    pub length: u32,
+}
+
+impl YamuxHeader {
+    pub const LEN: usize = 12;
+
+    pub fn parse(bytes: &[u8; Self::LEN]) -> io::Result<Self> {
+        let version = bytes[0];
+        if version != 0 {
+            let msg = format!("unknown yamux version {version}");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        let ty = match bytes[1] {
+            0 => YamuxFrameType::Data,
+            1 => YamuxFrameType::WindowUpdate,
+            2 => YamuxFrameType::Ping,
+            3 => YamuxFrameType::GoAway,
+            ty => {
+                let msg = format!("unknown yamux frame type {ty}");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        };
+        let flags = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let stream_id = u32::from_be_bytes(bytes[4..8].try_into().expect("cannot fail"));
+        let length = u32::from_be_bytes(bytes[8..12].try_into().expect("cannot fail"));
+
+        Ok(YamuxHeader {
+            ty,
+            flags,
+            stream_id,
+            length,
+        })
+    }
+
+    /// The length of the data that follows the header.
+    pub fn data_len(&self) -> usize {
+        if self.ty == YamuxFrameType::Data {
+            self.length as usize
+        } else {
+            0
+        }
+    }
+}
+
+/// Returns the value and the number of bytes it takes, `None` if incomplete.
+pub fn read_uvarint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().take(9).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Read the multistream-select message at the start of `bytes`, a varint length
+/// and a line. Returns the line without the newline and the number of bytes it takes,
+/// `None` if incomplete.
+pub fn multistream_message(bytes: &[u8]) -> Option<(String, usize)> {
+    let (len, offset) = read_uvarint(bytes)?;
+    let end = offset.checked_add(len)?;
+    let line = bytes.get(offset..end)?;
+    let line = String::from_utf8_lossy(line).trim_end().to_owned();
+    Some((line, end))
+}
+
+/// Whether the multistream-select message names a protocol,
+/// rather than the multistream header or a refusal.
+pub fn is_protocol(line: &str) -> bool {
+    !line.starts_with("/multistream/") && line != "na" && line != "ls" && !line.is_empty()
+}